@@ -0,0 +1,438 @@
+use crate::pfile::Pfile;
+use crate::writer::PfileWriter;
+use csv::{ReaderBuilder, StringRecord};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+/// The value of an annotation column for variants without a match.
+pub const MISSING_ANNOTATION: &str = ".";
+
+/// The .pvar columns identifying a variant, in the order of `VariantKey`.
+pub const VARIANT_KEY_COLUMNS: [&str; 5] = ["CHROM", "POS", "ID", "REF", "ALT"];
+
+/// The names of BED columns after the first three, per the UCSC spec.
+const BED_COLUMNS: [&str; 9] = [
+    "NAME",
+    "SCORE",
+    "STRAND",
+    "THICK_START",
+    "THICK_END",
+    "ITEM_RGB",
+    "BLOCK_COUNT",
+    "BLOCK_SIZES",
+    "BLOCK_STARTS",
+];
+
+/// The columns of a variant record which annotations are matched on.
+pub struct VariantKey<'a> {
+    pub chrom: &'a str,
+    pub pos: &'a str,
+    pub id: &'a str,
+    pub ref_allele: &'a str,
+    pub alt_allele: &'a str,
+}
+
+impl<'a> VariantKey<'a> {
+    /// The index of each of the `VARIANT_KEY_COLUMNS` among the .pvar columns,
+    /// if present.
+    pub fn column_idxs(var_header: &StringRecord) -> [Option<usize>; 5] {
+        VARIANT_KEY_COLUMNS.map(|name| var_header.iter().position(|col| col == name))
+    }
+
+    /// The key of a variant record, given the `column_idxs` of the .pvar.
+    pub fn from_record(col_idxs: &[Option<usize>; 5], var_rcd: &'a StringRecord) -> Self {
+        let [chrom, pos, id, ref_allele, alt_allele] = col_idxs.map(|col_idx| {
+            col_idx
+                .and_then(|col_idx| var_rcd.get(col_idx))
+                .unwrap_or(MISSING_ANNOTATION)
+        });
+        VariantKey {
+            chrom,
+            pos,
+            id,
+            ref_allele,
+            alt_allele,
+        }
+    }
+}
+
+/// Chromosome names with the `chr` prefix left out, so that e.g. `chr1` in
+/// an annotation file matches `1` in the .pvar.
+pub fn normalise_chrom(chrom: &str) -> &str {
+    chrom.strip_prefix("chr").unwrap_or(chrom)
+}
+
+/// BED intervals on a chromosome, sorted by start.
+#[derive(Default)]
+struct Intervals {
+    /// The 0-based start, exclusive end and row of each interval.
+    intervals: Vec<(u64, u64, usize)>,
+    /// The largest end among the intervals up to each one, so that the
+    /// search for overlaps can stop early.
+    max_ends: Vec<u64>,
+}
+
+impl Intervals {
+    fn sort(&mut self) {
+        self.intervals.sort_unstable();
+        let mut max_end = 0;
+        self.max_ends = self
+            .intervals
+            .iter()
+            .map(|(_start, end, _row_idx)| {
+                max_end = max_end.max(*end);
+                max_end
+            })
+            .collect();
+    }
+
+    /// The rows of the intervals holding the 1-based position `pos`, in
+    /// order of start.
+    fn overlapping(&self, pos: u64) -> Vec<usize> {
+        // the intervals starting at or before the (0-based) position
+        let num_before = self
+            .intervals
+            .partition_point(|(start, _end, _row_idx)| *start < pos);
+        let mut row_idxs = Vec::new();
+        for idx in (0..num_before).rev() {
+            if self.max_ends[idx] < pos {
+                break;
+            }
+            let (_start, end, row_idx) = self.intervals[idx];
+            if end >= pos {
+                row_idxs.push(row_idx);
+            }
+        }
+        row_idxs.reverse();
+        row_idxs
+    }
+}
+
+/// How the rows of an annotation file are matched to variants.
+enum AnnotationIndex {
+    /// By the ID column.
+    Id(HashMap<String, Vec<usize>>),
+    /// By the CHROM, POS, REF and ALT columns.
+    Alleles(HashMap<(String, String, String, String), Vec<usize>>),
+    /// By the BED intervals (on each chromosome) holding the variant.
+    Intervals(HashMap<String, Intervals>),
+}
+
+/// Columns joined onto the variants from an annotation file, which is one of
+///
+/// - a BED file (`.bed`), matched by overlap, whose columns after the first
+///   three are named as in the UCSC spec (`NAME`, `SCORE`, `STRAND`, ...).
+///   Intervals without a name are named after their 1-based range, as in
+///   `1:1001-2000`.
+/// - a VCF (`.vcf`), matched by CHROM, POS, REF and ALT (any of its ALT
+///   alleles), whose INFO fields are the columns.
+/// - a tab-separated file with a header line, matched by CHROM, POS, REF and
+///   ALT if it has those columns and otherwise by ID. Its other columns are
+///   the annotation columns.
+///
+/// Variants matching several rows get their values joined by commas.
+pub struct Annotations {
+    /// The path of the annotation file.
+    pub source: String,
+    pub columns: Vec<String>,
+    rows: Vec<Vec<String>>,
+    index: AnnotationIndex,
+}
+
+impl Annotations {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> csv::Result<Annotations> {
+        let path = path.as_ref();
+        let source = path.display().to_string();
+        let (columns, rows, index) = if source.ends_with(".bed") {
+            Annotations::read_bed(path)?
+        } else if source.ends_with(".vcf") {
+            Annotations::read_vcf(path)?
+        } else {
+            Annotations::read_tsv(path)?
+        };
+        Ok(Annotations {
+            source,
+            columns,
+            rows,
+            index,
+        })
+    }
+
+    fn read_bed(path: &Path) -> io::Result<(Vec<String>, Vec<Vec<String>>, AnnotationIndex)> {
+        let mut rows = Vec::new();
+        let mut by_chrom: HashMap<String, Intervals> = HashMap::new();
+        let mut num_cols = 0;
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if line.is_empty()
+                || line.starts_with('#')
+                || line.starts_with("track")
+                || line.starts_with("browser")
+            {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            let invalid = || {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid BED line in {}: {}", path.display(), line),
+                )
+            };
+            let [chrom, start, end] = [fields.first(), fields.get(1), fields.get(2)];
+            let (Some(chrom), Some(start), Some(end)) = (chrom, start, end) else {
+                return Err(invalid());
+            };
+            let start: u64 = start.parse().map_err(|_| invalid())?;
+            let end: u64 = end.parse().map_err(|_| invalid())?;
+            let mut values: Vec<String> = fields[3..].iter().map(|val| val.to_string()).collect();
+            if values.is_empty() {
+                values.push(format!("{}:{}-{}", chrom, start + 1, end));
+            }
+            num_cols = num_cols.max(values.len());
+            by_chrom
+                .entry(normalise_chrom(chrom).to_string())
+                .or_default()
+                .intervals
+                .push((start, end, rows.len()));
+            rows.push(values);
+        }
+        for intervals in by_chrom.values_mut() {
+            intervals.sort();
+        }
+        let columns = (0..num_cols)
+            .map(|col_idx| {
+                BED_COLUMNS
+                    .get(col_idx)
+                    .map_or_else(|| format!("BED{}", col_idx + 4), |col| col.to_string())
+            })
+            .collect();
+        Ok((columns, rows, AnnotationIndex::Intervals(by_chrom)))
+    }
+
+    fn read_vcf(path: &Path) -> io::Result<(Vec<String>, Vec<Vec<String>>, AnnotationIndex)> {
+        let mut columns: Vec<String> = Vec::new();
+        let mut col_idxs: HashMap<String, usize> = HashMap::new();
+        let mut rows = Vec::new();
+        let mut by_alleles: HashMap<(String, String, String, String), Vec<usize>> = HashMap::new();
+        for line in BufReader::new(File::open(path)?).lines() {
+            let line = line?;
+            if let Some(info) = line.strip_prefix("##INFO=<ID=") {
+                let id = info.split([',', '>']).next().unwrap_or(info);
+                if !col_idxs.contains_key(id) {
+                    col_idxs.insert(id.to_string(), columns.len());
+                    columns.push(id.to_string());
+                }
+                continue;
+            }
+            if line.starts_with('#') || line.is_empty() {
+                continue;
+            }
+            let fields: Vec<&str> = line.split('\t').collect();
+            if fields.len() < 8 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid VCF line in {}: {}", path.display(), line),
+                ));
+            }
+            let mut values = Vec::new();
+            for entry in fields[7].split(';').filter(|entry| *entry != ".") {
+                // flags have no value
+                let (key, val) = entry.split_once('=').unwrap_or((entry, "1"));
+                let col_idx = *col_idxs.entry(key.to_string()).or_insert_with(|| {
+                    columns.push(key.to_string());
+                    columns.len() - 1
+                });
+                if values.len() <= col_idx {
+                    values.resize(col_idx + 1, MISSING_ANNOTATION.to_string());
+                }
+                values[col_idx] = val.to_string();
+            }
+            for alt_allele in fields[4].split(',') {
+                let key = (
+                    normalise_chrom(fields[0]).to_string(),
+                    fields[1].to_string(),
+                    fields[3].to_string(),
+                    alt_allele.to_string(),
+                );
+                by_alleles.entry(key).or_default().push(rows.len());
+            }
+            rows.push(values);
+        }
+        for values in rows.iter_mut() {
+            values.resize(columns.len(), MISSING_ANNOTATION.to_string());
+        }
+        Ok((columns, rows, AnnotationIndex::Alleles(by_alleles)))
+    }
+
+    fn read_tsv(path: &Path) -> csv::Result<(Vec<String>, Vec<Vec<String>>, AnnotationIndex)> {
+        let mut reader = ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+        let headers: Vec<String> = reader
+            .headers()?
+            .iter()
+            .map(|col| col.trim().trim_start_matches('#').to_string())
+            .collect();
+        let [chrom_idx, pos_idx, id_idx, ref_idx, alt_idx] = VARIANT_KEY_COLUMNS.map(|name| {
+            headers
+                .iter()
+                .position(|col| col.eq_ignore_ascii_case(name))
+        });
+        let key_idxs = [chrom_idx, pos_idx, id_idx, ref_idx, alt_idx];
+        let value_idxs: Vec<usize> = (0..headers.len())
+            .filter(|col_idx| !key_idxs.contains(&Some(*col_idx)))
+            .collect();
+        let columns = value_idxs
+            .iter()
+            .map(|col_idx| headers[*col_idx].clone())
+            .collect();
+
+        let records = reader
+            .records()
+            .collect::<csv::Result<Vec<StringRecord>>>()?;
+        let rows = records
+            .iter()
+            .map(|rcd| {
+                value_idxs
+                    .iter()
+                    .map(|col_idx| rcd[*col_idx].to_string())
+                    .collect()
+            })
+            .collect();
+        let index = match (chrom_idx, pos_idx, ref_idx, alt_idx, id_idx) {
+            (Some(chrom_idx), Some(pos_idx), Some(ref_idx), Some(alt_idx), _) => {
+                let mut by_alleles: HashMap<_, Vec<usize>> = HashMap::new();
+                for (row_idx, rcd) in records.iter().enumerate() {
+                    let key = (
+                        normalise_chrom(&rcd[chrom_idx]).to_string(),
+                        rcd[pos_idx].to_string(),
+                        rcd[ref_idx].to_string(),
+                        rcd[alt_idx].to_string(),
+                    );
+                    by_alleles.entry(key).or_default().push(row_idx);
+                }
+                AnnotationIndex::Alleles(by_alleles)
+            }
+            (_, _, _, _, Some(id_idx)) => {
+                let mut by_id: HashMap<String, Vec<usize>> = HashMap::new();
+                for (row_idx, rcd) in records.iter().enumerate() {
+                    by_id
+                        .entry(rcd[id_idx].to_string())
+                        .or_default()
+                        .push(row_idx);
+                }
+                AnnotationIndex::Id(by_id)
+            }
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "neither ID nor CHROM, POS, REF and ALT among the headers of {}",
+                        path.display()
+                    ),
+                )
+                .into())
+            }
+        };
+        Ok((columns, rows, index))
+    }
+
+    /// The value of each annotation column for the given variant, or `None`
+    /// if it matches no row.
+    pub fn values(&self, key: &VariantKey) -> Option<Vec<String>> {
+        let row_idxs = match &self.index {
+            AnnotationIndex::Id(by_id) => by_id.get(key.id).cloned(),
+            AnnotationIndex::Alleles(by_alleles) => by_alleles
+                .get(&(
+                    normalise_chrom(key.chrom).to_string(),
+                    key.pos.to_string(),
+                    key.ref_allele.to_string(),
+                    key.alt_allele.to_string(),
+                ))
+                .cloned(),
+            AnnotationIndex::Intervals(by_chrom) => {
+                let intervals = by_chrom.get(normalise_chrom(key.chrom));
+                let pos = key.pos.parse().ok();
+                intervals
+                    .zip(pos)
+                    .map(|(intervals, pos)| intervals.overlapping(pos))
+                    .filter(|row_idxs| !row_idxs.is_empty())
+            }
+        }?;
+        Some(
+            (0..self.columns.len())
+                .map(|col_idx| {
+                    let values = row_idxs
+                        .iter()
+                        .filter_map(|row_idx| self.rows[*row_idx].get(col_idx))
+                        .map(String::as_str);
+                    values.collect::<Vec<&str>>().join(",")
+                })
+                .collect(),
+        )
+    }
+}
+
+impl Pfile {
+    /// Writes the kept variants and samples as a new pfile with prefix
+    /// `out_prefix`, with the columns of the `--annotations` file appended to
+    /// the .pvar (as `.` for variants without a match).
+    pub fn output_annotated_pfile(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let annotations = self.annotations.as_ref().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no --annotations were passed")
+        })?;
+
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        if let Some(col) = annotations
+            .columns
+            .iter()
+            .find(|col| pvar_headers.iter().any(|pvar_col| pvar_col == col.as_str()))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is already a column of {}", col, self.pvar_path()),
+            )
+            .into());
+        }
+        let mut out_pvar_headers = pvar_headers.clone();
+        for col in annotations.columns.iter() {
+            out_pvar_headers.push_field(col);
+        }
+        let key_col_idxs = VariantKey::column_idxs(&pvar_headers);
+        let mut pfile_writer = PfileWriter::create(
+            &out_prefix,
+            &sam_header,
+            &sam_idx_rcds,
+            self.num_samples as usize,
+            &self.read_pvar_header(),
+            &out_pvar_headers,
+        )?;
+
+        let mut record_reader = self.record_reader()?;
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, mut var_rcd) = var_idx_rcd?;
+            let values = annotations.values(&VariantKey::from_record(&key_col_idxs, &var_rcd));
+            match values {
+                Some(values) => values.iter().for_each(|val| var_rcd.push_field(val)),
+                None => annotations
+                    .columns
+                    .iter()
+                    .for_each(|_col| var_rcd.push_field(MISSING_ANNOTATION)),
+            }
+            pfile_writer.write_variant(&var_rcd, record_reader.record(var_idx)?)?;
+        }
+        pfile_writer.finish()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use crate::pfile::{genotype_code, Pfile};
+use crate::stats::fmt_stat;
+use nalgebra::{DMatrix, DVector};
+use statrs::distribution::{ContinuousCDF, Normal, StudentsT};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// The values read as missing in phenotype and covariate columns, on top of
+/// `0` in case/control phenotypes.
+const MISSING_VALUES: [&str; 6] = ["", ".", "NA", "na", "nan", "-9"];
+
+/// The maximum number of Newton steps when fitting a logistic regression.
+const LOGISTIC_MAX_ITERATIONS: usize = 25;
+
+/// Fitting a logistic regression stops once no coefficient changes by more
+/// than this.
+const LOGISTIC_TOLERANCE: f64 = 1e-8;
+
+/// The regression run on each variant, depending on the phenotype.
+#[derive(Clone, Copy)]
+enum Model {
+    /// For quantitative phenotypes.
+    Linear,
+    /// For case/control phenotypes.
+    Logistic,
+}
+
+/// The estimated effect of the ALT allele count on the phenotype, along with
+/// its standard error, test statistic and p-value.
+struct AddTest {
+    beta: f64,
+    se: f64,
+    stat: f64,
+    p: f64,
+}
+
+/// Fits the ordinary least squares regression of `y` on the columns of `x`,
+/// testing the coefficient of column 1 (that of the genotypes).
+fn fit_linear(x: &DMatrix<f64>, y: &DVector<f64>) -> Option<AddTest> {
+    let (num_obs, num_params) = x.shape();
+    if num_obs <= num_params {
+        return None;
+    }
+    let xtx = x.transpose() * x;
+    let xtx_inv = xtx.cholesky()?.inverse();
+    let coefs = &xtx_inv * (x.transpose() * y);
+    let residuals = y - x * &coefs;
+    let df = (num_obs - num_params) as f64;
+    let sigma2 = residuals.norm_squared() / df;
+    let se = (sigma2 * xtx_inv[(1, 1)]).sqrt();
+    let stat = coefs[1] / se;
+    let p = 2.0 * StudentsT::new(0.0, 1.0, df).ok()?.sf(stat.abs());
+    se.is_finite().then_some(AddTest {
+        beta: coefs[1],
+        se,
+        stat,
+        p,
+    })
+}
+
+/// Fits the logistic regression of `y` (0 or 1) on the columns of `x` with
+/// Newton's method, testing the coefficient of column 1 (that of the
+/// genotypes) with a Wald test.
+fn fit_logistic(x: &DMatrix<f64>, y: &DVector<f64>) -> Option<AddTest> {
+    let (num_obs, num_params) = x.shape();
+    if num_obs <= num_params {
+        return None;
+    }
+    // start from the intercept-only fit
+    let mean = y.mean();
+    let mut coefs = DVector::<f64>::zeros(num_params);
+    coefs[0] = (mean / (1.0 - mean)).ln();
+    let hessian_inv = |coefs: &DVector<f64>| {
+        let probs = (x * coefs).map(|eta| 1.0 / (1.0 + (-eta).exp()));
+        let weights = probs.map(|prob| prob * (1.0 - prob));
+        let weighted_x =
+            DMatrix::from_fn(num_obs, num_params, |row, col| x[(row, col)] * weights[row]);
+        let hessian = x.transpose() * weighted_x;
+        hessian.cholesky().map(|chol| (chol.inverse(), probs))
+    };
+    let mut converged = false;
+    for _ in 0..LOGISTIC_MAX_ITERATIONS {
+        let (hessian_inv, probs) = hessian_inv(&coefs)?;
+        let step = hessian_inv * (x.transpose() * (y - probs));
+        coefs += &step;
+        if step.amax() < LOGISTIC_TOLERANCE {
+            converged = true;
+            break;
+        }
+    }
+    if !converged || !coefs.iter().all(|coef| coef.is_finite()) {
+        return None;
+    }
+    let (hessian_inv, _probs) = hessian_inv(&coefs)?;
+    let se = hessian_inv[(1, 1)].sqrt();
+    let stat = coefs[1] / se;
+    let p = 2.0 * Normal::standard().sf(stat.abs());
+    se.is_finite().then_some(AddTest {
+        beta: coefs[1],
+        se,
+        stat,
+        p,
+    })
+}
+
+/// A kept sample with a phenotype and all the covariates.
+struct AnalysedSample {
+    sam_idx: usize,
+    pheno: f64,
+    covars: Vec<f64>,
+}
+
+impl Pfile {
+    /// The values of the given sample column for each kept sample, taken from
+    /// the `--pheno` file when it has the column and from the .psam
+    /// otherwise, with `None` for missing values.
+    fn sample_column_values(
+        &self,
+        sam_header: &csv::StringRecord,
+        sam_idx_rcds: &[(usize, csv::StringRecord)],
+        name: &str,
+    ) -> io::Result<Vec<Option<f64>>> {
+        let pheno = self.pheno.as_ref();
+        let sam_rcd_id_idx = self.sample_id_idx(sam_header)?;
+        let sam_rcd_fid_idx = sam_header.iter().position(|col| col == "FID");
+        let pheno_col_idx = pheno.and_then(|pheno| pheno.column(name));
+        let psam_col_idx = sam_header.iter().position(|col| col == name);
+        if pheno_col_idx.is_none() && psam_col_idx.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} not among the sample columns", name),
+            ));
+        }
+        sam_idx_rcds
+            .iter()
+            .map(|(_sam_idx, sam_rcd)| {
+                let value = match (pheno, pheno_col_idx) {
+                    (Some(pheno), Some(col_idx)) => {
+                        let fid = sam_rcd_fid_idx.map(|fid_idx| &sam_rcd[fid_idx]);
+                        pheno
+                            .get(fid, &sam_rcd[sam_rcd_id_idx])
+                            .map_or("", |row| &row[col_idx])
+                    }
+                    _ => &sam_rcd[psam_col_idx.unwrap()],
+                };
+                if MISSING_VALUES.contains(&value) {
+                    return Ok(None);
+                }
+                value.parse().map(Some).map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid {} value {}", name, value),
+                    )
+                })
+            })
+            .collect()
+    }
+
+    /// Tests each kept variant for association with the `pheno_name`
+    /// phenotype over the kept samples, adjusting for the `covar_names`
+    /// covariates, with an additive model of the ALT allele count.
+    ///
+    /// Phenotypes holding only 1 (control), 2 (case) and 0 (missing), with at
+    /// least one case and one control, are tested with logistic regression,
+    /// and any other with linear regression. Fails when no kept sample has a
+    /// value of the phenotype. Samples missing the phenotype or any covariate are left
+    /// out, as are samples missing the genotype of the variant being tested.
+    ///
+    /// Writes the results to `{out_prefix}.{pheno_name}.glm.linear` or
+    /// `{out_prefix}.{pheno_name}.glm.logistic`, as plink2's `--glm` does
+    /// (although always reporting the BETA of logistic regressions).
+    pub fn output_assoc(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        pheno_name: &str,
+        covar_names: &[String],
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+
+        let mut pheno_values = self.sample_column_values(&sam_header, &sam_idx_rcds, pheno_name)?;
+        if pheno_values.iter().all(Option::is_none) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("no kept sample has a {} value", pheno_name),
+            )
+            .into());
+        }
+        let has_value = |target: f64| pheno_values.iter().flatten().any(|value| *value == target);
+        let is_case_control = pheno_values
+            .iter()
+            .flatten()
+            .all(|value| [0.0, 1.0, 2.0].contains(value))
+            && has_value(1.0)
+            && has_value(2.0);
+        let model = if is_case_control {
+            for value in pheno_values.iter_mut() {
+                *value = value.filter(|value| *value != 0.0).map(|value| value - 1.0);
+            }
+            Model::Logistic
+        } else {
+            Model::Linear
+        };
+        let covar_values = covar_names
+            .iter()
+            .map(|name| self.sample_column_values(&sam_header, &sam_idx_rcds, name))
+            .collect::<io::Result<Vec<Vec<Option<f64>>>>>()?;
+
+        let analysed: Vec<AnalysedSample> = sam_idx_rcds
+            .iter()
+            .enumerate()
+            .filter_map(|(kept_idx, (sam_idx, _sam_rcd))| {
+                Some(AnalysedSample {
+                    sam_idx: *sam_idx,
+                    pheno: pheno_values[kept_idx]?,
+                    covars: covar_values
+                        .iter()
+                        .map(|values| values[kept_idx])
+                        .collect::<Option<Vec<f64>>>()?,
+                })
+            })
+            .collect();
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        let [chrom_idx, pos_idx, id_idx, ref_idx, alt_idx] = ["CHROM", "POS", "ID", "REF", "ALT"]
+            .map(|name| pvar_headers.iter().position(|col| col == name));
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        let (ext, stat_name) = match model {
+            Model::Linear => ("linear", "T_STAT"),
+            Model::Logistic => ("logistic", "Z_STAT"),
+        };
+        let glm = File::create(format!("{}.{}.glm.{}", out_prefix, pheno_name, ext))?;
+        let mut glm_writer = BufWriter::new(glm);
+        writeln!(
+            glm_writer,
+            "#CHROM\tPOS\tID\tREF\tALT\tA1\tTEST\tOBS_CT\tBETA\tSE\t{}\tP",
+            stat_name
+        )?;
+
+        let num_params = 2 + covar_names.len();
+        let mut record_reader = self.record_reader()?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, var_rcd) = var_idx_rcd?;
+            let col = |col_idx: Option<usize>| {
+                col_idx
+                    .and_then(|col_idx| var_rcd.get(col_idx))
+                    .unwrap_or(".")
+            };
+            let record_buf = record_reader.record(var_idx)?;
+
+            // the design matrix and phenotypes of the samples called
+            let called: Vec<(f64, &AnalysedSample)> = analysed
+                .iter()
+                .filter_map(|sample| {
+                    let code = genotype_code(record_buf, sample.sam_idx);
+                    (code != 0b11).then_some((code as f64, sample))
+                })
+                .collect();
+            let x = DMatrix::from_fn(called.len(), num_params, |row, col| match col {
+                0 => 1.0,
+                1 => called[row].0,
+                _ => called[row].1.covars[col - 2],
+            });
+            let y = DVector::from_iterator(
+                called.len(),
+                called.iter().map(|(_code, sample)| sample.pheno),
+            );
+            let test = match model {
+                Model::Linear => fit_linear(&x, &y),
+                Model::Logistic => fit_logistic(&x, &y),
+            };
+
+            writeln!(
+                glm_writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\tADD\t{}\t{}\t{}\t{}\t{}",
+                col(chrom_idx),
+                col(pos_idx),
+                col(id_idx),
+                col(ref_idx),
+                col(alt_idx),
+                col(alt_idx),
+                called.len(),
+                fmt_stat(test.as_ref().map(|test| test.beta)),
+                fmt_stat(test.as_ref().map(|test| test.se)),
+                fmt_stat(test.as_ref().map(|test| test.stat)),
+                fmt_stat(test.as_ref().map(|test| test.p)),
+            )?;
+        }
+        glm_writer.flush()?;
+        Ok(())
+    }
+}
@@ -1,12 +1,41 @@
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+
+    #[arg(long = "mmap", global = true)]
+    /// When passed, memory maps the .pgen instead of reading each variant
+    /// record from it. Falls back to reading if mapping the file fails.
+    pub mmap: bool,
+
+    #[arg(long = "threads", global = true, default_value = "1")]
+    /// The number of threads rendering the VCF lines of the kept variants,
+    /// for the subcommands writing a VCF.
+    pub threads: NonZeroUsize,
+
+    #[arg(long, global = true)]
+    /// A tab-separated file of further sample columns (e.g. phenotypes,
+    /// covariates or QC flags), with a header line naming an IID column and
+    /// optionally a FID column. Its other columns are variables of the
+    /// --include-sam expressions (and of query --samples), as `NA` for
+    /// samples missing from it.
+    pub pheno: Option<PathBuf>,
+
+    #[arg(long, global = true)]
+    /// A file of further variant columns (e.g. gene names or functional
+    /// consequences): a BED file (.bed) matched by overlap, a VCF (.vcf)
+    /// matched by CHROM, POS, REF and ALT, or a tab-separated file with a
+    /// header line, matched by CHROM, POS, REF and ALT or else by ID. Its
+    /// columns are variables of the --include-var expressions (and of query
+    /// over the variants), as `.` for variants without a match, and INFO
+    /// fields of VCF output.
+    pub annotations: Option<PathBuf>,
 }
 
 #[derive(Subcommand)]
@@ -19,7 +48,9 @@ pub enum Commands {
     /// in the fstring and query.
     Query {
         /// The prefix of the pgen file triples. There should be three files
-        /// PFILE_PREFIX.pgen, PFILE_PREFIX.psam, and PFILE_PREFIX.pvar.
+        /// PFILE_PREFIX.pgen, PFILE_PREFIX.psam, and PFILE_PREFIX.pvar (the
+        /// latter two may instead be Zstandard-compressed, as .psam.zst and
+        /// .pvar.zst).
         pfile_prefix: String,
 
         #[arg(short = 'f', long = "fstring")]
@@ -41,9 +72,271 @@ pub enum Commands {
     /// querying the variants, CHROM and ID are variables which contain their
     /// respective values.
     Filter {
-        /// The prefix of the pgen file triples. There should be three files
-        /// PFILE_PREFIX.pgen, PFILE_PREFIX.psam, and PFILE_PREFIX.pvar.
-        pfile_prefix: String,
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(short = 'o', long = "out")]
+        /// The output file name (defaults to PFILE_PREFIX.pgen-rs.vcf)
+        out_file: Option<PathBuf>,
+
+        #[arg(
+            long = "id-paste",
+            value_enum,
+            value_delimiter = ',',
+            default_value = "iid"
+        )]
+        /// The comma-separated .psam columns pasted together into the sample
+        /// names of the VCF, as in plink2's --export id-paste (e.g.
+        /// maybe-fid,iid,maybe-sid). maybe-fid and maybe-sid are only included
+        /// when the column is present with some value other than 0.
+        id_paste: Vec<IdPart>,
+
+        #[arg(long = "id-delim", default_value = "_")]
+        /// The delimiter between the pasted columns of the sample names.
+        id_delim: char,
+    },
+    /// Exports the genotypes of the kept variants and samples to a format
+    /// meant for downstream analysis.
+    ///
+    /// The expressions are the same as in filter.
+    Export {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long = "format", value_enum)]
+        /// The format to export to.
+        format: ExportFormat,
+
+        #[arg(long = "sample-major")]
+        /// When passed, the npy and tsv formats write one row per sample and
+        /// one column per variant instead of the other way around.
+        sample_major: bool,
+
+        #[arg(long = "long")]
+        /// When passed, the parquet and arrow formats additionally write the
+        /// genotypes as a long (ID, IID, GT) table to OUT_PREFIX.genotypes.EXT.
+        long: bool,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Computes per-variant allele frequencies, missingness and genotype
+    /// counts over the kept samples, or per-sample QC statistics over the
+    /// kept variants.
+    ///
+    /// Writes OUT_PREFIX.afreq, OUT_PREFIX.vmiss and OUT_PREFIX.gcount, with
+    /// the same column names as plink2's --freq, --missing and --geno-counts.
+    /// With --samples writes OUT_PREFIX.smiss and OUT_PREFIX.het instead.
+    /// The expressions are the same as in filter.
+    Stats {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long)]
+        /// When passed, computes the missing call rate, het and hom alt
+        /// counts, and inbreeding coefficient F of each kept sample instead.
+        samples: bool,
+
+        #[arg(long, conflicts_with = "samples")]
+        /// When passed, also runs the exact test for Hardy-Weinberg
+        /// equilibrium on each kept variant, writing OUT_PREFIX.hardy.
+        hwe: bool,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Computes linkage disequilibrium (r² and D′) between pairs of kept
+    /// variants within a window, over the kept samples.
+    ///
+    /// Writes the pairs with an r² of at least --r2-min to OUT_PREFIX.vcor,
+    /// with the same column names as plink2's --r2-unphased. The expressions
+    /// are the same as in filter.
+    Ld {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long, default_value_t = 1_000_000)]
+        /// Only compares variants on the same chromosome at most this many
+        /// base pairs apart.
+        window_bp: u64,
+
+        #[arg(long, default_value_t = 10)]
+        /// Only compares variants at most this many kept variants apart.
+        window_variants: usize,
+
+        #[arg(long = "index-var")]
+        /// When passed, only compares the variant with this ID to the others
+        /// in its window, instead of comparing all pairs.
+        index_var: Option<String>,
+
+        #[arg(long, default_value_t = 0.2)]
+        /// Only writes the pairs with at least this r².
+        r2_min: f64,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Prunes the kept variants by linkage disequilibrium over the kept
+    /// samples, like plink2's --indep-pairwise.
+    ///
+    /// Slides a window of --window kept variants along each chromosome,
+    /// --step variants at a time, pruning the variant with the lower minor
+    /// allele frequency of every pair in it with an r² above --r2. Writes
+    /// the IDs of the remaining variants to OUT_PREFIX.prune.in and those of
+    /// the pruned ones to OUT_PREFIX.prune.out. The expressions are the same
+    /// as in filter.
+    Prune {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long, default_value = "50")]
+        /// The number of kept variants in each window.
+        window: NonZeroUsize,
+
+        #[arg(long, default_value = "5")]
+        /// The number of kept variants to move the window by each time.
+        step: NonZeroUsize,
+
+        #[arg(long, default_value_t = 0.2)]
+        /// Prunes one variant of every pair with an r² above this.
+        r2: f64,
+
+        #[arg(long)]
+        /// When passed, also writes the remaining variants and kept samples
+        /// to OUT_PREFIX.vcf.
+        vcf: bool,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Computes the genomic relationship matrix (GRM) of the kept samples
+    /// over the kept variants, as GCTA does.
+    ///
+    /// The expressions are the same as in filter.
+    Grm {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long, value_enum, default_value_t = GrmFormat::Gcta)]
+        /// The format to write the GRM in.
+        format: GrmFormat,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Computes the top principal components of the kept samples from the
+    /// standardized genotypes of the kept variants.
+    ///
+    /// The components are approximated by randomized subspace iteration,
+    /// which reads the genotypes of the kept variants 11 times (10 power
+    /// iterations and a final pass).
+    ///
+    /// Writes the components to OUT_PREFIX.eigenvec and their eigenvalues to
+    /// OUT_PREFIX.eigenval, as plink2's --pca does. The expressions are the
+    /// same as in filter.
+    Pca {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(short = 'n', long = "n", default_value_t = 10)]
+        /// The number of principal components to compute.
+        num_pcs: usize,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Computes the KING-robust kinship coefficient of every pair of kept
+    /// samples over the kept variants.
+    ///
+    /// Writes the pairs to OUT_PREFIX.kin0, with the same column names as
+    /// plink2's --make-king-table. The expressions are the same as in filter.
+    Kinship {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long)]
+        /// When passed, only writes the pairs with at least this kinship
+        /// coefficient (e.g. 0.0884 for second degree relatives or closer).
+        min_kinship: Option<f64>,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Tests each variant for association with a phenotype, by linear
+    /// regression for quantitative phenotypes and logistic regression for
+    /// case/control phenotypes (coded 1 for controls, 2 for cases and 0 for
+    /// missing), optionally adjusting for covariates. The phenotype and
+    /// covariates are read from the --pheno file or the .psam. Writes
+    /// OUT_PREFIX.PHENO_NAME.glm.linear or OUT_PREFIX.PHENO_NAME.glm.logistic.
+    /// The expressions are the same as in filter.
+    Assoc {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long, default_value = "PHENO1")]
+        /// The name of the phenotype column.
+        pheno_name: String,
+
+        #[arg(long, value_delimiter = ',')]
+        /// The names of the covariate columns, separated by commas.
+        covar: Vec<String>,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Computes polygenic scores for each sample, summing the effect allele
+    /// counts of the variants in a score file times their weights. Missing
+    /// genotypes count as the mean effect allele count. Writes
+    /// OUT_PREFIX.sscore. The expressions are the same as in filter.
+    Score {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        /// A tab-separated file with a header line, holding the variant IDs,
+        /// effect alleles and weights. The effect allele may be the REF or
+        /// the ALT allele.
+        score_file: PathBuf,
+
+        #[arg(long, default_value = "ID")]
+        /// The name of the variant ID column of the score file.
+        id_col: String,
+
+        #[arg(long, default_value = "A1")]
+        /// The name of the effect allele column of the score file.
+        allele_col: String,
+
+        #[arg(long, value_delimiter = ',', default_value = "WEIGHT")]
+        /// The names of the weight columns of the score file, separated by
+        /// commas, each giving a score.
+        weight_col: Vec<String>,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Writes the kept variants and samples as a new pfile with the columns
+    /// of the --annotations file appended to the .pvar, or as a VCF with
+    /// them in the INFO field. The expressions are the same as in filter.
+    Annotate {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long)]
+        /// When passed, writes OUT_PREFIX.vcf instead of OUT_PREFIX.pgen,
+        /// OUT_PREFIX.psam and OUT_PREFIX.pvar.
+        vcf: bool,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+    /// Writes the kept variants of several pfiles with the same samples, one
+    /// after the other, as a single pfile or VCF. The expressions are the same
+    /// as in filter.
+    Concat {
+        #[arg(required = true, num_args = 2..)]
+        /// The prefixes of the pgen file triples, in the order their variants
+        /// are written. Their .psam files must list the same samples in the
+        /// same order.
+        pfile_prefixes: Vec<String>,
 
         #[arg(long = "include-var")]
         /// An expression specifying which variants to keep. If not passed,
@@ -55,8 +348,148 @@ pub enum Commands {
         /// keeps all samples.
         sam_query: Option<String>,
 
+        #[arg(long)]
+        /// When passed, writes OUT_PREFIX.vcf instead of OUT_PREFIX.pgen,
+        /// OUT_PREFIX.psam and OUT_PREFIX.pvar.
+        vcf: bool,
+
         #[arg(short = 'o', long = "out")]
-        /// The output file name (defaults to PFILE_PREFIX.pgen-rs.vcf)
-        out_file: Option<PathBuf>,
+        /// The prefix of the output files (defaults to the first
+        /// PFILE_PREFIXES.pgen-rs)
+        out_prefix: Option<String>,
+    },
+    /// Merges several pfiles into one with the union of their samples and of
+    /// their variants, the latter matched by CHROM, POS, REF and ALT (either
+    /// way round). Samples get missing genotypes for variants of the pfiles
+    /// they are not in. The expressions are the same as in filter, and apply
+    /// to each pfile.
+    Merge {
+        #[arg(required = true, num_args = 2..)]
+        /// The prefixes of the pgen file triples. The samples of the earlier
+        /// ones come first, and the earlier ones take precedence for the .psam
+        /// and .pvar columns and non-missing genotypes. The variants are
+        /// sorted by chromosome and position.
+        pfile_prefixes: Vec<String>,
+
+        #[arg(long = "include-var")]
+        /// An expression specifying which variants to keep. If not passed,
+        /// keeps all variants.
+        var_query: Option<String>,
+
+        #[arg(long = "include-sam")]
+        /// An expression specifying which samples to keep. If not passed,
+        /// keeps all samples.
+        sam_query: Option<String>,
+
+        #[arg(short = 'o', long = "out")]
+        /// The prefix of the output files (defaults to the first
+        /// PFILE_PREFIXES.pgen-rs)
+        out_prefix: Option<String>,
     },
+    /// Splits the kept variants by chromosome or into chunks, writing each
+    /// group with the kept samples as a pfile or VCF. The expressions are the
+    /// same as in filter.
+    Split {
+        #[command(flatten)]
+        pfile_args: PfileArgs,
+
+        #[arg(long, value_enum, required_unless_present = "chunks")]
+        /// Writes one output per chromosome, to OUT_PREFIX.CHROM. The variants
+        /// of each chromosome must be contiguous in the .pvar.
+        by: Option<SplitBy>,
+
+        #[arg(long, conflicts_with = "by")]
+        /// Writes this many outputs of consecutive variants, as equal in size
+        /// as possible, to OUT_PREFIX.chunk1, OUT_PREFIX.chunk2 and so on.
+        chunks: Option<NonZeroUsize>,
+
+        #[arg(long)]
+        /// When passed, writes a VCF (with a .vcf extension) per group instead
+        /// of a pfile.
+        vcf: bool,
+
+        #[command(flatten)]
+        out_args: OutArgs,
+    },
+}
+
+/// The pfile and expressions of the subcommands reading the variants and
+/// samples of a single pfile.
+#[derive(Args)]
+pub struct PfileArgs {
+    /// The prefix of the pgen file triples. There should be three files
+    /// PFILE_PREFIX.pgen, PFILE_PREFIX.psam, and PFILE_PREFIX.pvar (the
+    /// latter two may instead be Zstandard-compressed, as .psam.zst and
+    /// .pvar.zst).
+    pub pfile_prefix: String,
+
+    #[arg(long = "include-var")]
+    /// An expression specifying which variants to keep. If not passed,
+    /// keeps all variants.
+    pub var_query: Option<String>,
+
+    #[arg(long = "include-sam")]
+    /// An expression specifying which samples to keep. If not passed,
+    /// keeps all samples.
+    pub sam_query: Option<String>,
+}
+
+/// The output prefix of the subcommands writing several files.
+#[derive(Args)]
+pub struct OutArgs {
+    #[arg(short = 'o', long = "out")]
+    /// The prefix of the output files (defaults to PFILE_PREFIX.pgen-rs)
+    pub out_prefix: Option<String>,
+}
+
+impl OutArgs {
+    /// The output prefix, defaulting to `{pfile_prefix}.pgen-rs`.
+    pub fn out_prefix_or_default(self, pfile_prefix: &str) -> String {
+        self.out_prefix
+            .unwrap_or_else(|| format!("{}.pgen-rs", pfile_prefix))
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    /// A variant-major int8 matrix (0/1/2 ALT allele counts, -1 for missing)
+    /// in OUT_PREFIX.npy, with the kept metadata in OUT_PREFIX.variants.tsv
+    /// and OUT_PREFIX.samples.tsv.
+    Npy,
+    /// A variant-major TSV matrix (0/1/2 ALT allele counts, NA for missing)
+    /// in OUT_PREFIX.tsv, with a header line of IIDs and the variant ID
+    /// leading each line. The kept metadata goes to the same sidecars as npy.
+    Tsv,
+    /// Typed Parquet tables of the kept metadata in
+    /// OUT_PREFIX.variants.parquet and OUT_PREFIX.samples.parquet.
+    Parquet,
+    /// Typed Arrow IPC tables of the kept metadata in
+    /// OUT_PREFIX.variants.arrow and OUT_PREFIX.samples.arrow.
+    Arrow,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum IdPart {
+    Fid,
+    MaybeFid,
+    Iid,
+    Sid,
+    MaybeSid,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum GrmFormat {
+    /// GCTA's binary lower triangle in OUT_PREFIX.grm.bin, with the number of
+    /// variants behind each entry in OUT_PREFIX.grm.N.bin and the FIDs and
+    /// IIDs in OUT_PREFIX.grm.id.
+    Gcta,
+    /// plink2's text lower triangle in OUT_PREFIX.rel, with the sample IDs in
+    /// OUT_PREFIX.rel.id.
+    Rel,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum SplitBy {
+    /// One group per chromosome, in order of appearance.
+    Chrom,
 }
@@ -0,0 +1,155 @@
+use crate::pfile::{self, Pfile};
+use crate::writer::{union_header_lines, PfileWriter};
+use csv::StringRecord;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// Checks that the .psam of each pfile lists the same samples (by FID, IID
+/// and SID, a missing FID or SID column reading as `0`) in the same order as
+/// that of the first.
+fn check_same_samples(pfiles: &[Pfile]) -> csv::Result<()> {
+    let mut first_ids: Option<Vec<[String; 3]>> = None;
+    for pfile in pfiles {
+        let mut psam_reader = pfile.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let col_idx = |name: &str| sam_header.iter().position(|col| col == name);
+        let id_col_idxs = [
+            col_idx("FID"),
+            Some(pfile.sample_id_idx(&sam_header)?),
+            col_idx("SID"),
+        ];
+        let ids = psam_reader
+            .records()
+            .map(|sam_rcd| {
+                sam_rcd.map(|sam_rcd| {
+                    id_col_idxs.map(|col_idx| {
+                        col_idx
+                            .and_then(|col_idx| sam_rcd.get(col_idx))
+                            .unwrap_or("0")
+                            .to_string()
+                    })
+                })
+            })
+            .collect::<csv::Result<Vec<[String; 3]>>>()?;
+        let Some(first_ids) = first_ids.as_ref() else {
+            first_ids = Some(ids);
+            continue;
+        };
+        if ids != *first_ids {
+            let mismatch = std::iter::zip(first_ids, &ids)
+                .find(|(first_id, id)| first_id != id)
+                .map_or_else(
+                    || format!("{} instead of {} samples", ids.len(), first_ids.len()),
+                    |(first_id, id)| {
+                        format!(
+                            "FID {}, IID {} and SID {} instead of {}, {} and {}",
+                            id[0], id[1], id[2], first_id[0], first_id[1], first_id[2]
+                        )
+                    },
+                );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "the samples of {} differ from those of {} ({})",
+                    pfile.psam_path(),
+                    pfiles[0].psam_path(),
+                    mismatch
+                ),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Writes the kept variants of each pfile, in the order given, as a single
+/// pfile `{out_prefix}.pgen`, `.psam` and `.pvar`, or as `{out_prefix}.vcf`
+/// when `vcf` is set. The pfiles must all have the same samples, which are
+/// filtered (and taken from) the first .psam.
+///
+/// The .pvar (or VCF) has the `##` header lines of all the .pvar files, each
+/// line (or contig, INFO field and so on) once, and the .pvar the columns of
+/// all of them, in order of appearance, with `.` for the variants of pfiles
+/// lacking one. When all samples are kept the genotype records are copied
+/// as they are.
+pub fn output_concat(
+    pfiles: &[Pfile],
+    sam_query: Option<String>,
+    var_query: Option<String>,
+    out_prefix: String,
+    vcf: bool,
+) -> csv::Result<()> {
+    check_same_samples(pfiles)?;
+    let first = &pfiles[0];
+    let mut psam_reader = first.psam_reader()?;
+    let sam_header = psam_reader.headers()?.clone();
+    let sam_idx_rcds = first.filter_samples(&mut psam_reader, sam_query)?;
+    let pvar_header_lines = union_header_lines(
+        &pfiles
+            .iter()
+            .map(|pfile| pfile.read_pvar_header())
+            .collect::<Vec<String>>(),
+    );
+
+    if vcf {
+        let sam_ids = first
+            .sample_names(
+                &sam_header,
+                &sam_idx_rcds,
+                &pfile::DEFAULT_ID_PASTE,
+                pfile::DEFAULT_ID_DELIM,
+            )?
+            .join("\t");
+        let vcf = File::create(format!("{}.vcf", out_prefix))?;
+        let mut vcf_writer = BufWriter::new(vcf);
+        first.write_vcf_header(&mut vcf_writer, &pvar_header_lines, &sam_ids)?;
+        for pfile in pfiles {
+            pfile.write_vcf_body(&mut vcf_writer, &sam_idx_rcds, var_query.clone())?;
+        }
+        vcf_writer.flush()?;
+        return Ok(());
+    }
+
+    let mut out_pvar_headers = StringRecord::new();
+    for pfile in pfiles {
+        for col in pfile.pvar_reader()?.headers()?.iter() {
+            if !out_pvar_headers.iter().any(|out_col| out_col == col) {
+                out_pvar_headers.push_field(col);
+            }
+        }
+    }
+    let mut pfile_writer = PfileWriter::create(
+        &out_prefix,
+        &sam_header,
+        &sam_idx_rcds,
+        first.num_samples as usize,
+        &pvar_header_lines,
+        &out_pvar_headers,
+    )?;
+    for pfile in pfiles {
+        let mut pvar_reader = pfile.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        // where each output column is in the records of this .pvar
+        let col_idxs = out_pvar_headers
+            .iter()
+            .map(|out_col| pvar_headers.iter().position(|col| col == out_col))
+            .collect::<Vec<Option<usize>>>();
+        let mut record_reader = pfile.record_reader()?;
+        let var_idx_rcds =
+            pfile.filter_variants_iter(&mut pvar_reader, var_query.clone(), &sam_idx_rcds)?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, var_rcd) = var_idx_rcd?;
+            let out_rcd: StringRecord = col_idxs
+                .iter()
+                .map(|col_idx| {
+                    col_idx
+                        .and_then(|col_idx| var_rcd.get(col_idx))
+                        .unwrap_or(".")
+                })
+                .collect();
+            pfile_writer.write_variant(&out_rcd, record_reader.record(var_idx)?)?;
+        }
+    }
+    pfile_writer.finish()?;
+    Ok(())
+}
@@ -0,0 +1,464 @@
+use crate::pfile::{genotype_code, Pfile};
+use arrow_array::builder::{ArrayBuilder, Int8Builder, StringBuilder};
+use arrow_array::{ArrayRef, Float64Array, Int64Array, RecordBatch, StringArray};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{DataType, Field, Schema, SchemaRef};
+use csv::StringRecord;
+use parquet::arrow::ArrowWriter;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::Arc;
+
+/// Columnar formats the metadata and long-format genotypes can be exported to.
+#[derive(Clone, Copy)]
+pub enum TableFormat {
+    Parquet,
+    /// The Arrow IPC file format (a.k.a. Feather v2).
+    Arrow,
+}
+
+impl TableFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            TableFormat::Parquet => "parquet",
+            TableFormat::Arrow => "arrow",
+        }
+    }
+}
+
+/// Columns which are always kept as strings, even when all of their values
+/// happen to look like numbers (e.g. CHROM in a file with only autosomes).
+const STRING_COLUMNS: [&str; 11] = [
+    "CHROM", "ID", "REF", "ALT", "FILTER", "INFO", "FID", "IID", "SID", "PAT", "MAT",
+];
+
+/// Number of rows in each record batch of the long-format genotype table.
+const GENOTYPE_BATCH_ROWS: usize = 1 << 20;
+
+/// ALT allele counts of each 2-bit genotype code, with -1 for missing.
+const DOSAGES: [i8; 4] = [0, 1, 2, -1];
+
+/// Upper bound on the size of the (samples × variants) tile held in memory
+/// when writing sample-major matrices.
+const TRANSPOSE_TILE_BYTES: usize = 1 << 28;
+
+/// Number of variant records read before transposing them into the tile.
+const TRANSPOSE_VARIANT_BLOCK: usize = 64;
+
+/// Formats the dense genotype matrix can be exported to.
+#[derive(Clone, Copy)]
+pub enum MatrixFormat {
+    /// A C-order `int8` NumPy array.
+    Npy,
+    /// A TSV with a header line of column ids and the row id leading each
+    /// line.
+    Tsv,
+}
+
+impl Pfile {
+    /// Exports the genotypes of the kept variants and samples as a dense
+    /// matrix, writing `{out_prefix}.npy` or `{out_prefix}.tsv`.
+    ///
+    /// Genotypes are encoded as the number of ALT alleles (0, 1 or 2), with
+    /// -1 (NA in TSVs) for missing calls. Rows are variants and columns are
+    /// samples, unless `sample_major` is set, in which case the matrix is
+    /// transposed. The metadata of the kept variants and samples goes to the
+    /// `{out_prefix}.variants.tsv` and `{out_prefix}.samples.tsv` sidecars, in
+    /// the same order as the matrix.
+    pub fn output_matrix(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        out_prefix: String,
+        format: MatrixFormat,
+        sample_major: bool,
+    ) -> csv::Result<()> {
+        let mut pvar_reader = self.pvar_reader()?;
+        let mut psam_reader = self.psam_reader()?;
+        let var_rcd_id_idx = self.variant_id_idx(pvar_reader.headers()?);
+        let sam_rcd_id_idx = self.sample_id_idx(psam_reader.headers()?)?;
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+        let var_idx_rcds = self.filter_variants(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        write_metadata_tsv(
+            format!("{}.variants.tsv", out_prefix),
+            pvar_reader.headers()?,
+            &var_idx_rcds,
+        )?;
+        write_metadata_tsv(
+            format!("{}.samples.tsv", out_prefix),
+            psam_reader.headers()?,
+            &sam_idx_rcds,
+        )?;
+
+        let var_ids = var_idx_rcds
+            .iter()
+            .map(|(_idx, rcd)| rcd.get(var_rcd_id_idx).unwrap())
+            .collect::<Vec<&str>>();
+        let sam_ids = sam_idx_rcds
+            .iter()
+            .map(|(_idx, rcd)| rcd.get(sam_rcd_id_idx).unwrap())
+            .collect::<Vec<&str>>();
+        let mut record_reader = self.record_reader()?;
+
+        if !sample_major {
+            let mut writer = MatrixWriter::create(&out_prefix, format, "ID", &var_ids, &sam_ids)?;
+            let mut row_buf = vec![0i8; sam_idx_rcds.len()];
+            for ((var_idx, _var_rcd), var_id) in var_idx_rcds.iter().zip(&var_ids) {
+                let record_buf = record_reader.record(*var_idx)?;
+                for (dosage, (sam_idx, _sam_rcd)) in row_buf.iter_mut().zip(sam_idx_rcds.iter()) {
+                    *dosage = DOSAGES[genotype_code(record_buf, *sam_idx) as usize];
+                }
+                writer.write_row(var_id, &row_buf)?;
+            }
+            return writer.finish();
+        }
+
+        // Transposing needs every variant before the first row can be written,
+        // so we make one pass over the .pgen per block of samples, holding only
+        // a (block samples × kept variants) tile in memory. Each pass reads just
+        // the bytes of the records covering the block.
+        let mut writer = MatrixWriter::create(&out_prefix, format, "IID", &sam_ids, &var_ids)?;
+        let num_kept_variants = var_idx_rcds.len();
+        // a row stride of at least 1 keeps chunks() happy when no variants are kept
+        let tile_stride = num_kept_variants.max(1);
+        // no bigger than needed for the kept samples, so small exports stay small
+        let block_samples =
+            (TRANSPOSE_TILE_BYTES / tile_stride).clamp(1, sam_idx_rcds.len().max(1));
+        let mut tile = vec![0i8; block_samples * tile_stride];
+        for (sam_block, sam_block_ids) in sam_idx_rcds
+            .chunks(block_samples)
+            .zip(sam_ids.chunks(block_samples))
+        {
+            // kept samples are in .psam order, so the block spans these bytes
+            let first_byte = sam_block[0].0 / 4;
+            let last_byte = sam_block[sam_block.len() - 1].0 / 4;
+            let part_size = last_byte - first_byte + 1;
+            let mut parts_buf = vec![0u8; TRANSPOSE_VARIANT_BLOCK * part_size];
+
+            let var_blocks = var_idx_rcds.chunks(TRANSPOSE_VARIANT_BLOCK);
+            for (var_block_idx, var_block) in var_blocks.enumerate() {
+                for ((var_idx, _var_rcd), part) in
+                    var_block.iter().zip(parts_buf.chunks_mut(part_size))
+                {
+                    part.copy_from_slice(
+                        record_reader.record_part(*var_idx, first_byte, part_size)?,
+                    );
+                }
+                // fill a run of each tile row at a time, the (small) parts
+                // buffer stays in cache while we stride over it
+                let run_start = var_block_idx * TRANSPOSE_VARIANT_BLOCK;
+                let run_end = run_start + var_block.len();
+                for ((sam_idx, _sam_rcd), tile_row) in
+                    sam_block.iter().zip(tile.chunks_mut(tile_stride))
+                {
+                    let in_part_idx = sam_idx - first_byte * 4;
+                    for (dosage, part) in tile_row[run_start..run_end]
+                        .iter_mut()
+                        .zip(parts_buf.chunks(part_size))
+                    {
+                        *dosage = DOSAGES[genotype_code(part, in_part_idx) as usize];
+                    }
+                }
+            }
+
+            for (sam_id, tile_row) in sam_block_ids.iter().zip(tile.chunks(tile_stride)) {
+                writer.write_row(sam_id, &tile_row[..num_kept_variants])?;
+            }
+        }
+        writer.finish()
+    }
+
+    /// Exports the kept .pvar and .psam records as typed tables, writing
+    /// `{out_prefix}.variants.{ext}` and `{out_prefix}.samples.{ext}`.
+    ///
+    /// When `long` is set, the genotypes of the kept subset are additionally
+    /// written to `{out_prefix}.genotypes.{ext}` with one (ID, IID, GT) row
+    /// per variant and sample, where GT is the ALT allele count or null if
+    /// missing.
+    pub fn output_tables(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        out_prefix: String,
+        format: TableFormat,
+        long: bool,
+    ) -> csv::Result<()> {
+        let mut pvar_reader = self.pvar_reader()?;
+        let mut psam_reader = self.psam_reader()?;
+        let sam_rcd_id_idx = self.sample_id_idx(psam_reader.headers()?)?;
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+        let var_idx_rcds = self.filter_variants(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        let ext = format.extension();
+        let variants = metadata_batch(pvar_reader.headers()?, &var_idx_rcds)?;
+        let mut writer = TableWriter::create(
+            format!("{}.variants.{}", out_prefix, ext),
+            variants.schema(),
+            format,
+        )?;
+        writer.write(&variants)?;
+        writer.finish()?;
+
+        let samples = metadata_batch(psam_reader.headers()?, &sam_idx_rcds)?;
+        let mut writer = TableWriter::create(
+            format!("{}.samples.{}", out_prefix, ext),
+            samples.schema(),
+            format,
+        )?;
+        writer.write(&samples)?;
+        writer.finish()?;
+
+        if !long {
+            return Ok(());
+        }
+        let var_rcd_id_idx = self.variant_id_idx(pvar_reader.headers()?);
+        let schema = Arc::new(Schema::new(vec![
+            Field::new("ID", DataType::Utf8, false),
+            Field::new("IID", DataType::Utf8, false),
+            Field::new("GT", DataType::Int8, true),
+        ]));
+        let mut writer = TableWriter::create(
+            format!("{}.genotypes.{}", out_prefix, ext),
+            schema.clone(),
+            format,
+        )?;
+        let mut record_reader = self.record_reader()?;
+        let mut id_builder = StringBuilder::new();
+        let mut iid_builder = StringBuilder::new();
+        let mut gt_builder = Int8Builder::new();
+        for (var_idx, var_rcd) in var_idx_rcds.iter() {
+            let record_buf = record_reader.record(*var_idx)?;
+            let var_id = var_rcd.get(var_rcd_id_idx).unwrap();
+            for (sam_idx, sam_rcd) in sam_idx_rcds.iter() {
+                id_builder.append_value(var_id);
+                iid_builder.append_value(sam_rcd.get(sam_rcd_id_idx).unwrap());
+                match genotype_code(record_buf, *sam_idx) {
+                    0b00 => gt_builder.append_value(0),
+                    0b01 => gt_builder.append_value(1),
+                    0b10 => gt_builder.append_value(2),
+                    0b11 => gt_builder.append_null(),
+                    _ => panic!("unexpected genotype"),
+                }
+            }
+            if gt_builder.len() >= GENOTYPE_BATCH_ROWS {
+                let batch =
+                    genotype_batch(&schema, &mut id_builder, &mut iid_builder, &mut gt_builder)?;
+                writer.write(&batch)?;
+            }
+        }
+        if gt_builder.len() > 0 {
+            let batch =
+                genotype_batch(&schema, &mut id_builder, &mut iid_builder, &mut gt_builder)?;
+            writer.write(&batch)?;
+        }
+        writer.finish()?;
+        Ok(())
+    }
+}
+
+/// A writer of record batches to any of the `TableFormat`s.
+enum TableWriter {
+    Parquet(ArrowWriter<File>),
+    Arrow(FileWriter<BufWriter<File>>),
+}
+
+impl TableWriter {
+    fn create(filename: String, schema: SchemaRef, format: TableFormat) -> io::Result<TableWriter> {
+        let file = File::create(filename)?;
+        let writer = match format {
+            TableFormat::Parquet => TableWriter::Parquet(
+                ArrowWriter::try_new(file, schema, None).map_err(io::Error::other)?,
+            ),
+            TableFormat::Arrow => TableWriter::Arrow(
+                FileWriter::try_new_buffered(file, &schema).map_err(io::Error::other)?,
+            ),
+        };
+        Ok(writer)
+    }
+
+    fn write(&mut self, batch: &RecordBatch) -> io::Result<()> {
+        match self {
+            TableWriter::Parquet(writer) => writer.write(batch).map_err(io::Error::other),
+            TableWriter::Arrow(writer) => writer.write(batch).map_err(io::Error::other),
+        }
+    }
+
+    fn finish(self) -> io::Result<()> {
+        match self {
+            TableWriter::Parquet(writer) => writer.close().map(|_| ()).map_err(io::Error::other),
+            TableWriter::Arrow(mut writer) => writer.finish().map_err(io::Error::other),
+        }
+    }
+}
+
+/// Builds a record batch out of the kept metadata records, typing each column
+/// as an integer, a float or a string depending on its values.
+///
+/// The missing value markers `.` and `NA` become nulls.
+fn metadata_batch(
+    headers: &StringRecord,
+    idx_rcds: &[(usize, StringRecord)],
+) -> io::Result<RecordBatch> {
+    let mut fields = Vec::new();
+    let mut columns: Vec<ArrayRef> = Vec::new();
+    for (col_idx, col_name) in headers.iter().enumerate() {
+        let values = idx_rcds
+            .iter()
+            .map(|(_idx, rcd)| match rcd.get(col_idx) {
+                Some(".") | Some("NA") | None => None,
+                val => val,
+            })
+            .collect::<Vec<Option<&str>>>();
+        let non_null = || values.iter().flatten();
+        let column: ArrayRef = if STRING_COLUMNS.contains(&col_name) {
+            Arc::new(StringArray::from(values))
+        } else if non_null().all(|val| val.parse::<i64>().is_ok()) {
+            Arc::new(Int64Array::from_iter(
+                values
+                    .iter()
+                    .map(|val| val.map(|val| val.parse::<i64>().unwrap())),
+            ))
+        } else if non_null().all(|val| val.parse::<f64>().is_ok()) {
+            Arc::new(Float64Array::from_iter(
+                values
+                    .iter()
+                    .map(|val| val.map(|val| val.parse::<f64>().unwrap())),
+            ))
+        } else {
+            Arc::new(StringArray::from(values))
+        };
+        fields.push(Field::new(col_name, column.data_type().clone(), true));
+        columns.push(column);
+    }
+    RecordBatch::try_new(Arc::new(Schema::new(fields)), columns).map_err(io::Error::other)
+}
+
+fn genotype_batch(
+    schema: &SchemaRef,
+    id_builder: &mut StringBuilder,
+    iid_builder: &mut StringBuilder,
+    gt_builder: &mut Int8Builder,
+) -> io::Result<RecordBatch> {
+    RecordBatch::try_new(
+        schema.clone(),
+        vec![
+            Arc::new(id_builder.finish()),
+            Arc::new(iid_builder.finish()),
+            Arc::new(gt_builder.finish()),
+        ],
+    )
+    .map_err(io::Error::other)
+}
+
+/// A writer of dense genotype matrices, one row at a time.
+enum MatrixWriter {
+    Npy(BufWriter<File>),
+    Tsv(BufWriter<File>),
+}
+
+impl MatrixWriter {
+    /// Creates `{out_prefix}.{ext}` and writes the header of a matrix with the
+    /// given row and column ids. `id_name` names the row id column in TSVs.
+    fn create(
+        out_prefix: &str,
+        format: MatrixFormat,
+        id_name: &str,
+        row_ids: &[&str],
+        col_ids: &[&str],
+    ) -> io::Result<MatrixWriter> {
+        match format {
+            MatrixFormat::Npy => {
+                let npy = File::create(format!("{}.npy", out_prefix))?;
+                let mut npy_writer = BufWriter::new(npy);
+                write_npy_header(&mut npy_writer, row_ids.len(), col_ids.len())?;
+                Ok(MatrixWriter::Npy(npy_writer))
+            }
+            MatrixFormat::Tsv => {
+                let tsv = File::create(format!("{}.tsv", out_prefix))?;
+                let mut tsv_writer = BufWriter::new(tsv);
+                tsv_writer.write_all(id_name.as_bytes())?;
+                for col_id in col_ids {
+                    tsv_writer.write_all(b"\t")?;
+                    tsv_writer.write_all(col_id.as_bytes())?;
+                }
+                tsv_writer.write_all(b"\n")?;
+                Ok(MatrixWriter::Tsv(tsv_writer))
+            }
+        }
+    }
+
+    fn write_row(&mut self, row_id: &str, dosages: &[i8]) -> io::Result<()> {
+        match self {
+            MatrixWriter::Npy(npy_writer) => {
+                let bytes = dosages
+                    .iter()
+                    .map(|dosage| *dosage as u8)
+                    .collect::<Vec<u8>>();
+                npy_writer.write_all(&bytes)
+            }
+            MatrixWriter::Tsv(tsv_writer) => {
+                tsv_writer.write_all(row_id.as_bytes())?;
+                for dosage in dosages {
+                    let cell: &[u8] = match dosage {
+                        0 => b"\t0",
+                        1 => b"\t1",
+                        2 => b"\t2",
+                        _ => b"\tNA",
+                    };
+                    tsv_writer.write_all(cell)?;
+                }
+                tsv_writer.write_all(b"\n")
+            }
+        }
+    }
+
+    fn finish(self) -> csv::Result<()> {
+        match self {
+            MatrixWriter::Npy(mut writer) | MatrixWriter::Tsv(mut writer) => writer.flush()?,
+        }
+        Ok(())
+    }
+}
+
+/// Writes the header of a version 1.0 .npy file holding a C-order `int8`
+/// matrix of the given shape.
+///
+/// See https://numpy.org/doc/stable/reference/generated/numpy.lib.format.html
+fn write_npy_header<W: Write>(writer: &mut W, num_rows: usize, num_cols: usize) -> io::Result<()> {
+    let dict = format!(
+        "{{'descr': '|i1', 'fortran_order': False, 'shape': ({}, {}), }}",
+        num_rows, num_cols
+    );
+    // magic string (6) + version (2) + header length (2) + dict + '\n' must be
+    // a multiple of 64 bytes, padding the dict with spaces
+    let unpadded_len = 10 + dict.len() + 1;
+    let padding = unpadded_len.next_multiple_of(64) - unpadded_len;
+    let header_len = (dict.len() + padding + 1) as u16;
+
+    writer.write_all(b"\x93NUMPY")?;
+    writer.write_all(&[1, 0])?;
+    writer.write_all(&header_len.to_le_bytes())?;
+    writer.write_all(dict.as_bytes())?;
+    writer.write_all(" ".repeat(padding).as_bytes())?;
+    writer.write_all(b"\n")
+}
+
+/// Writes the kept metadata records as a TSV with a plain (not `#`-prefixed)
+/// header line, so that it can be read directly by pandas and friends.
+fn write_metadata_tsv(
+    filename: String,
+    headers: &StringRecord,
+    idx_rcds: &[(usize, StringRecord)],
+) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .quote_style(csv::QuoteStyle::Never)
+        .from_path(filename)?;
+    writer.write_record(headers)?;
+    for (_idx, rcd) in idx_rcds {
+        writer.write_record(rcd)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use crate::pfile::{genotype_code, Pfile};
+use crate::stats::GenotypeCounts;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Number of variants standardized and added to the GRM at a time, which
+/// bounds the memory used on top of the GRM itself.
+const GRM_CHUNK_VARIANTS: usize = 256;
+
+/// Formats the GRM can be written in.
+#[derive(Clone, Copy)]
+pub enum GrmFormat {
+    /// GCTA's binary `.grm.bin`, `.grm.N.bin` and `.grm.id`.
+    Gcta,
+    /// plink2's lower triangular text `.rel` and `.rel.id`.
+    Rel,
+}
+
+/// The standardized genotypes of a chunk of variants among the kept samples.
+///
+/// Both are sample-major, so that the contributions of the chunk to each
+/// pair of samples come from two contiguous rows.
+struct StandardizedChunk {
+    num_samples: usize,
+    num_variants: usize,
+    /// The standardized ALT allele counts, 0 where missing.
+    values: Vec<f64>,
+    /// Bit masks of the variants called for each sample.
+    called: Vec<u64>,
+    /// The sum over the chunk of GCTA's diagonal estimator for each sample,
+    /// 0 where missing.
+    diag: Vec<f64>,
+}
+
+impl StandardizedChunk {
+    const CALLED_WORDS: usize = GRM_CHUNK_VARIANTS / 64;
+
+    fn new(num_samples: usize) -> StandardizedChunk {
+        StandardizedChunk {
+            num_samples,
+            num_variants: 0,
+            values: vec![0f64; num_samples * GRM_CHUNK_VARIANTS],
+            called: vec![0u64; num_samples * StandardizedChunk::CALLED_WORDS],
+            diag: vec![0f64; num_samples],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.num_variants = 0;
+        self.values.fill(0.0);
+        self.called.fill(0);
+        self.diag.fill(0.0);
+    }
+
+    fn is_full(&self) -> bool {
+        self.num_variants == GRM_CHUNK_VARIANTS
+    }
+
+    /// Adds the variant with the given packed record to the chunk, unless it
+    /// is monomorphic among the kept samples.
+    ///
+    /// The diagonal takes GCTA's estimator
+    /// 1 + (x² - (1 + 2p)x + 2p²) / (2p(1 - p)) rather than the square of the
+    /// standardized count.
+    fn push(&mut self, record_buf: &[u8], sam_idxs: &[usize], counts: &GenotypeCounts) {
+        let Some((mean, sd)) = counts.standardization() else {
+            return;
+        };
+        let var_pos = self.num_variants;
+        for (kept_idx, sam_idx) in sam_idxs.iter().enumerate() {
+            let code = genotype_code(record_buf, *sam_idx);
+            if code == 0b11 {
+                continue;
+            }
+            let alt_count = code as f64;
+            self.values[kept_idx * GRM_CHUNK_VARIANTS + var_pos] = (alt_count - mean) / sd;
+            self.diag[kept_idx] += 1.0
+                + (alt_count * alt_count - (1.0 + mean) * alt_count + mean * mean / 2.0)
+                    / (sd * sd);
+            self.called[kept_idx * StandardizedChunk::CALLED_WORDS + var_pos / 64] |=
+                1 << (var_pos % 64);
+        }
+        self.num_variants += 1;
+    }
+
+    /// Adds the products of the standardized genotypes of every pair of
+    /// samples (in the lower triangle) and the diagonal estimators to `sums`,
+    /// and the number of variants called for both to `counts`.
+    fn add_to(&self, sums: &mut [f64], counts: &mut [u32]) {
+        let mut tri_idx = 0;
+        for sam_i in 0..self.num_samples {
+            let row_i = &self.values[sam_i * GRM_CHUNK_VARIANTS..(sam_i + 1) * GRM_CHUNK_VARIANTS];
+            let called_i = &self.called[sam_i * StandardizedChunk::CALLED_WORDS
+                ..(sam_i + 1) * StandardizedChunk::CALLED_WORDS];
+            for sam_j in 0..=sam_i {
+                let row_j =
+                    &self.values[sam_j * GRM_CHUNK_VARIANTS..(sam_j + 1) * GRM_CHUNK_VARIANTS];
+                let called_j = &self.called[sam_j * StandardizedChunk::CALLED_WORDS
+                    ..(sam_j + 1) * StandardizedChunk::CALLED_WORDS];
+                sums[tri_idx] += if sam_j == sam_i {
+                    self.diag[sam_i]
+                } else {
+                    dot(row_i, row_j)
+                };
+                counts[tri_idx] += std::iter::zip(called_i, called_j)
+                    .map(|(called_i, called_j)| (called_i & called_j).count_ones())
+                    .sum::<u32>();
+                tri_idx += 1;
+            }
+        }
+    }
+}
+
+/// The dot product of two rows, summed in independent lanes so that it can
+/// be vectorized.
+fn dot(row_i: &[f64], row_j: &[f64]) -> f64 {
+    let mut lanes = [0f64; 8];
+    for (chunk_i, chunk_j) in std::iter::zip(row_i.chunks_exact(8), row_j.chunks_exact(8)) {
+        for lane in 0..8 {
+            lanes[lane] += chunk_i[lane] * chunk_j[lane];
+        }
+    }
+    lanes.iter().sum()
+}
+
+impl Pfile {
+    /// Computes the genomic relationship matrix of the kept samples over the
+    /// kept variants, as in GCTA: the relationship of each pair of samples is
+    /// the mean, over the variants called for both, of the products of their
+    /// ALT allele counts standardized by the ALT allele frequency among the
+    /// kept samples, and the diagonal the mean of GCTA's estimator of the
+    /// inbreeding of each sample plus one. Monomorphic variants are skipped,
+    /// and the entries without any variant called for both samples are NaN.
+    ///
+    /// Writes the lower triangle (diagonal included) in the given `format`,
+    /// to `{out_prefix}.grm.*` or `{out_prefix}.rel*`.
+    pub fn output_grm(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        out_prefix: String,
+        format: GrmFormat,
+    ) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_rcd_id_idx = self.sample_id_idx(&sam_header)?;
+        let sam_rcd_fid_idx = sam_header.iter().position(|col| col == "FID");
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+        let sam_idxs: Vec<usize> = sam_idx_rcds.iter().map(|(sam_idx, _)| *sam_idx).collect();
+        let num_kept_samples = sam_idxs.len();
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        let num_pairs = num_kept_samples * (num_kept_samples + 1) / 2;
+        let mut sums = vec![0f64; num_pairs];
+        let mut counts = vec![0u32; num_pairs];
+        let mut chunk = StandardizedChunk::new(num_kept_samples);
+        let mut record_reader = self.record_reader()?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, _var_rcd) = var_idx_rcd?;
+            let record_buf = record_reader.record(var_idx)?;
+            let var_counts =
+                GenotypeCounts::from_record(record_buf, &sam_idx_rcds, self.num_samples as usize);
+            chunk.push(record_buf, &sam_idxs, &var_counts);
+            if chunk.is_full() {
+                chunk.add_to(&mut sums, &mut counts);
+                chunk.clear();
+            }
+        }
+        if chunk.num_variants > 0 {
+            chunk.add_to(&mut sums, &mut counts);
+        }
+        let grm = std::iter::zip(&sums, &counts).map(|(sum, count)| match count {
+            0 => f64::NAN,
+            count => sum / *count as f64,
+        });
+
+        // GCTA has no header line, so without FIDs we repeat the IIDs as
+        // plink 1 does
+        let sam_ids = sam_idx_rcds.iter().map(|(_sam_idx, sam_rcd)| {
+            let iid = &sam_rcd[sam_rcd_id_idx];
+            (sam_rcd_fid_idx.map(|fid_idx| &sam_rcd[fid_idx]), iid)
+        });
+        match format {
+            GrmFormat::Gcta => {
+                let mut grm_writer =
+                    BufWriter::new(File::create(format!("{}.grm.bin", out_prefix))?);
+                for value in grm {
+                    grm_writer.write_all(&(value as f32).to_le_bytes())?;
+                }
+                grm_writer.flush()?;
+                let mut n_writer =
+                    BufWriter::new(File::create(format!("{}.grm.N.bin", out_prefix))?);
+                for count in counts.iter() {
+                    n_writer.write_all(&(*count as f32).to_le_bytes())?;
+                }
+                n_writer.flush()?;
+                let mut id_writer = BufWriter::new(File::create(format!("{}.grm.id", out_prefix))?);
+                for (fid, iid) in sam_ids {
+                    writeln!(id_writer, "{}\t{}", fid.unwrap_or(iid), iid)?;
+                }
+                id_writer.flush()?;
+            }
+            GrmFormat::Rel => {
+                let mut rel_writer = BufWriter::new(File::create(format!("{}.rel", out_prefix))?);
+                let mut row_len = 1;
+                let mut row_pos = 0;
+                for value in grm {
+                    row_pos += 1;
+                    if row_pos == row_len {
+                        writeln!(rel_writer, "{}", value)?;
+                        row_len += 1;
+                        row_pos = 0;
+                    } else {
+                        write!(rel_writer, "{}\t", value)?;
+                    }
+                }
+                rel_writer.flush()?;
+                let mut id_writer = BufWriter::new(File::create(format!("{}.rel.id", out_prefix))?);
+                if sam_rcd_fid_idx.is_some() {
+                    writeln!(id_writer, "#FID\tIID")?;
+                } else {
+                    writeln!(id_writer, "#IID")?;
+                }
+                for (fid, iid) in sam_ids {
+                    match fid {
+                        Some(fid) => writeln!(id_writer, "{}\t{}", fid, iid)?,
+                        None => writeln!(id_writer, "{}", iid)?,
+                    }
+                }
+                id_writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+}
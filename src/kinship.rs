@@ -0,0 +1,205 @@
+use crate::pfile::{genotype_code, Pfile};
+use crate::stats::fmt_stat;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// Number of variants transposed into per-sample bit masks at a time, which
+/// bounds the memory used on top of the per-pair counts.
+const KING_CHUNK_VARIANTS: usize = 1024;
+
+/// Number of 64 variant words in the masks of each sample in a chunk.
+const KING_CHUNK_WORDS: usize = KING_CHUNK_VARIANTS / 64;
+
+/// The genotypes of a chunk of variants as bit masks (one bit per variant)
+/// for each kept sample.
+struct GenotypeBits {
+    num_samples: usize,
+    num_variants: usize,
+    hom_ref: Vec<u64>,
+    het: Vec<u64>,
+    hom_alt: Vec<u64>,
+}
+
+impl GenotypeBits {
+    fn new(num_samples: usize) -> GenotypeBits {
+        GenotypeBits {
+            num_samples,
+            num_variants: 0,
+            hom_ref: vec![0u64; num_samples * KING_CHUNK_WORDS],
+            het: vec![0u64; num_samples * KING_CHUNK_WORDS],
+            hom_alt: vec![0u64; num_samples * KING_CHUNK_WORDS],
+        }
+    }
+
+    fn clear(&mut self) {
+        self.num_variants = 0;
+        self.hom_ref.fill(0);
+        self.het.fill(0);
+        self.hom_alt.fill(0);
+    }
+
+    fn is_full(&self) -> bool {
+        self.num_variants == KING_CHUNK_VARIANTS
+    }
+
+    /// Adds the genotypes of the given samples in a packed variant record to
+    /// the chunk.
+    fn push(&mut self, record_buf: &[u8], sam_idxs: &[usize]) {
+        let word = self.num_variants / 64;
+        let bit = 1u64 << (self.num_variants % 64);
+        for (kept_idx, sam_idx) in sam_idxs.iter().enumerate() {
+            let masks = match genotype_code(record_buf, *sam_idx) {
+                0b00 => &mut self.hom_ref,
+                0b01 => &mut self.het,
+                0b10 => &mut self.hom_alt,
+                _ => continue,
+            };
+            masks[kept_idx * KING_CHUNK_WORDS + word] |= bit;
+        }
+        self.num_variants += 1;
+    }
+
+    /// The masks of the sample at `kept_idx`.
+    fn sample(&self, kept_idx: usize) -> [&[u64]; 3] {
+        let words = kept_idx * KING_CHUNK_WORDS..(kept_idx + 1) * KING_CHUNK_WORDS;
+        [
+            &self.hom_ref[words.clone()],
+            &self.het[words.clone()],
+            &self.hom_alt[words],
+        ]
+    }
+
+    /// Adds the counts of every pair of samples over the chunk to `pairs`
+    /// (ordered as in `PairCounts`).
+    fn add_to(&self, pairs: &mut [PairCounts]) {
+        let mut pair_idx = 0;
+        for sam_j in 0..self.num_samples {
+            let [hom_ref_j, het_j, hom_alt_j] = self.sample(sam_j);
+            for sam_i in 0..sam_j {
+                let [hom_ref_i, het_i, hom_alt_i] = self.sample(sam_i);
+                let pair = &mut pairs[pair_idx];
+                for word in 0..KING_CHUNK_WORDS {
+                    let called_i = hom_ref_i[word] | het_i[word] | hom_alt_i[word];
+                    let called_j = hom_ref_j[word] | het_j[word] | hom_alt_j[word];
+                    pair.both_called += (called_i & called_j).count_ones();
+                    pair.het_het += (het_i[word] & het_j[word]).count_ones();
+                    pair.ibs0 += ((hom_ref_i[word] & hom_alt_j[word])
+                        | (hom_alt_i[word] & hom_ref_j[word]))
+                        .count_ones();
+                    pair.het_i += (het_i[word] & called_j).count_ones();
+                    pair.het_j += (het_j[word] & called_i).count_ones();
+                }
+                pair_idx += 1;
+            }
+        }
+    }
+}
+
+/// The counts over the variants called for both samples of a pair (i, j),
+/// with i < j. The pairs are ordered by j and then by i.
+#[derive(Clone, Copy, Default)]
+struct PairCounts {
+    both_called: u32,
+    het_het: u32,
+    ibs0: u32,
+    het_i: u32,
+    het_j: u32,
+}
+
+impl PairCounts {
+    /// The KING-robust (between-family) kinship coefficient of Manichaikul
+    /// et al. (2010), or `None` if either sample has no het call.
+    fn kinship(&self) -> Option<f64> {
+        let min_het = self.het_i.min(self.het_j) as f64;
+        (min_het > 0.0).then(|| {
+            (self.het_het as f64 - 2.0 * self.ibs0 as f64) / (2.0 * min_het) + 0.5
+                - (self.het_i + self.het_j) as f64 / (4.0 * min_het)
+        })
+    }
+}
+
+impl Pfile {
+    /// Computes the KING-robust kinship coefficient of every pair of kept
+    /// samples over the kept variants, writing those with a coefficient of
+    /// at least `min_kinship` (if passed) to `{out_prefix}.kin0`, with the
+    /// column names of plink2's `--make-king-table`.
+    pub fn output_kinship(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        min_kinship: Option<f64>,
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_rcd_id_idx = self.sample_id_idx(&sam_header)?;
+        let sam_rcd_fid_idx = sam_header.iter().position(|col| col == "FID");
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+        let sam_idxs: Vec<usize> = sam_idx_rcds.iter().map(|(sam_idx, _)| *sam_idx).collect();
+        let num_kept_samples = sam_idxs.len();
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        let num_pairs = num_kept_samples * num_kept_samples.saturating_sub(1) / 2;
+        let mut pairs = vec![PairCounts::default(); num_pairs];
+        let mut chunk = GenotypeBits::new(num_kept_samples);
+        let mut record_reader = self.record_reader()?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, _var_rcd) = var_idx_rcd?;
+            chunk.push(record_reader.record(var_idx)?, &sam_idxs);
+            if chunk.is_full() {
+                chunk.add_to(&mut pairs);
+                chunk.clear();
+            }
+        }
+        if chunk.num_variants > 0 {
+            chunk.add_to(&mut pairs);
+        }
+
+        let kin0 = File::create(format!("{}.kin0", out_prefix))?;
+        let mut kin0_writer = BufWriter::new(kin0);
+        if sam_rcd_fid_idx.is_some() {
+            writeln!(
+                kin0_writer,
+                "#FID1\tIID1\tFID2\tIID2\tNSNP\tHETHET\tIBS0\tKINSHIP"
+            )?;
+        } else {
+            writeln!(kin0_writer, "#IID1\tIID2\tNSNP\tHETHET\tIBS0\tKINSHIP")?;
+        }
+        let sam_id = |kept_idx: usize| {
+            let sam_rcd = &sam_idx_rcds[kept_idx].1;
+            match sam_rcd_fid_idx {
+                Some(fid_idx) => format!("{}\t{}", &sam_rcd[fid_idx], &sam_rcd[sam_rcd_id_idx]),
+                None => sam_rcd[sam_rcd_id_idx].to_string(),
+            }
+        };
+        let mut pair_idx = 0;
+        for sam_j in 0..num_kept_samples {
+            for sam_i in 0..sam_j {
+                let pair = &pairs[pair_idx];
+                pair_idx += 1;
+                let kinship = pair.kinship();
+                // plink2 reports HETHET and IBS0 as proportions of NSNP
+                let both_called = (pair.both_called > 0).then_some(pair.both_called as f64);
+                let hethet = both_called.map(|both_called| pair.het_het as f64 / both_called);
+                let ibs0 = both_called.map(|both_called| pair.ibs0 as f64 / both_called);
+                if min_kinship.is_some_and(|min_kinship| kinship.is_none_or(|k| k < min_kinship)) {
+                    continue;
+                }
+                writeln!(
+                    kin0_writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}",
+                    sam_id(sam_i),
+                    sam_id(sam_j),
+                    pair.both_called,
+                    fmt_stat(hethet),
+                    fmt_stat(ibs0),
+                    fmt_stat(kinship)
+                )?;
+            }
+        }
+        kin0_writer.flush()?;
+        Ok(())
+    }
+}
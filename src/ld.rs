@@ -0,0 +1,415 @@
+use crate::pfile::{genotype_code, Pfile};
+use csv::StringRecord;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// The low bit of each of the 32 genotypes packed in a word.
+const LOW_BITS: u64 = 0x5555_5555_5555_5555;
+
+/// Which pairs of variants `output_ld` compares.
+pub struct LdWindow {
+    /// The largest distance in base pairs between the variants of a pair.
+    pub max_bp: u64,
+    /// The largest distance in kept variants between the variants of a pair.
+    pub max_variants: usize,
+}
+
+/// The sliding window of `prune_ld`.
+pub struct PruneWindow {
+    /// The number of kept variants in each window.
+    pub size: usize,
+    /// The number of kept variants the window moves by each time.
+    pub step: usize,
+}
+
+/// The genotypes of a variant among the kept samples, as masks over words of
+/// 32 packed 2-bit genotypes (with only the low bit of each set).
+struct GenotypeMasks {
+    het: Vec<u64>,
+    hom_alt: Vec<u64>,
+    called: Vec<u64>,
+}
+
+impl GenotypeMasks {
+    /// Splits the genotypes of the given samples in a packed variant record
+    /// into masks.
+    ///
+    /// When all `num_samples` samples are kept the words are read straight
+    /// out of the record, otherwise the kept samples are packed first.
+    fn from_record(
+        record_buf: &[u8],
+        sam_idx_rcds: &[(usize, StringRecord)],
+        num_samples: usize,
+    ) -> GenotypeMasks {
+        let words: Vec<u64> = if sam_idx_rcds.len() == num_samples {
+            record_buf
+                .chunks(8)
+                .map(|chunk| {
+                    let mut bytes = [0u8; 8];
+                    bytes[..chunk.len()].copy_from_slice(chunk);
+                    u64::from_le_bytes(bytes)
+                })
+                .collect()
+        } else {
+            let mut words = vec![0u64; sam_idx_rcds.len().div_ceil(32)];
+            for (kept_idx, (sam_idx, _sam_rcd)) in sam_idx_rcds.iter().enumerate() {
+                let code = genotype_code(record_buf, *sam_idx) as u64;
+                words[kept_idx / 32] |= code << ((kept_idx % 32) * 2);
+            }
+            words
+        };
+
+        let num_words = words.len();
+        let mut masks = GenotypeMasks {
+            het: Vec::with_capacity(num_words),
+            hom_alt: Vec::with_capacity(num_words),
+            called: Vec::with_capacity(num_words),
+        };
+        for (word_idx, word) in words.into_iter().enumerate() {
+            let low = word & LOW_BITS;
+            let high = (word >> 1) & LOW_BITS;
+            // the padding after the last sample reads as hom ref, so it is
+            // left out of the called genotypes
+            let remaining_samples = sam_idx_rcds.len() - word_idx * 32;
+            let sample_bits = if remaining_samples < 32 {
+                (1u64 << (remaining_samples * 2)) - 1
+            } else {
+                u64::MAX
+            };
+            masks.het.push(low & !high);
+            masks.hom_alt.push(high & !low);
+            masks.called.push(!(low & high) & LOW_BITS & sample_bits);
+        }
+        masks
+    }
+
+    /// The minor allele frequency over the called samples, or 0 if no sample
+    /// was called.
+    fn maf(&self) -> f64 {
+        let called: u32 = self.called.iter().map(|mask| mask.count_ones()).sum();
+        let alt_alleles: u32 = std::iter::zip(&self.het, &self.hom_alt)
+            .map(|(het, hom_alt)| het.count_ones() + 2 * hom_alt.count_ones())
+            .sum();
+        if called == 0 {
+            return 0.0;
+        }
+        let alt_freq = alt_alleles as f64 / (2 * called) as f64;
+        alt_freq.min(1.0 - alt_freq)
+    }
+
+    /// The r² and D′ between two variants, computed from the correlation of
+    /// their ALT allele counts over the samples called for both. `None` when
+    /// either variant is monomorphic among those samples.
+    ///
+    /// Without phased haplotypes, D is estimated as half the covariance of
+    /// the allele counts (which is exact under Hardy-Weinberg equilibrium).
+    fn ld(&self, other: &GenotypeMasks) -> Option<(f64, f64)> {
+        let (mut n, mut sum_x, mut sum_xx, mut sum_y, mut sum_yy, mut sum_xy) =
+            (0u64, 0u64, 0u64, 0u64, 0u64, 0u64);
+        for word_idx in 0..self.called.len() {
+            let called = self.called[word_idx] & other.called[word_idx];
+            let (het_x, hom_alt_x) = (self.het[word_idx] & called, self.hom_alt[word_idx] & called);
+            let (het_y, hom_alt_y) = (
+                other.het[word_idx] & called,
+                other.hom_alt[word_idx] & called,
+            );
+            let pop = |mask: u64| mask.count_ones() as u64;
+            n += pop(called);
+            sum_x += pop(het_x) + 2 * pop(hom_alt_x);
+            sum_xx += pop(het_x) + 4 * pop(hom_alt_x);
+            sum_y += pop(het_y) + 2 * pop(hom_alt_y);
+            sum_yy += pop(het_y) + 4 * pop(hom_alt_y);
+            sum_xy += pop(het_x & het_y)
+                + 2 * pop((het_x & hom_alt_y) | (hom_alt_x & het_y))
+                + 4 * pop(hom_alt_x & hom_alt_y);
+        }
+
+        let n = n as f64;
+        let (sum_x, sum_xx, sum_y, sum_yy, sum_xy) = (
+            sum_x as f64,
+            sum_xx as f64,
+            sum_y as f64,
+            sum_yy as f64,
+            sum_xy as f64,
+        );
+        let var_x = n * sum_xx - sum_x * sum_x;
+        let var_y = n * sum_yy - sum_y * sum_y;
+        if var_x <= 0.0 || var_y <= 0.0 {
+            return None;
+        }
+        let cov = n * sum_xy - sum_x * sum_y;
+        let r2 = cov * cov / (var_x * var_y);
+
+        let d = cov / (n * n) / 2.0;
+        let (freq_x, freq_y) = (sum_x / (2.0 * n), sum_y / (2.0 * n));
+        let d_max = if d > 0.0 {
+            (freq_x * (1.0 - freq_y)).min((1.0 - freq_x) * freq_y)
+        } else {
+            (freq_x * freq_y).min((1.0 - freq_x) * (1.0 - freq_y))
+        };
+        let d_prime = (d / d_max).clamp(-1.0, 1.0);
+        Some((r2, d_prime))
+    }
+}
+
+/// A kept variant, along with its position among the kept variants.
+struct LdVariant {
+    kept_idx: usize,
+    chrom: String,
+    pos: u64,
+    id: String,
+    masks: GenotypeMasks,
+}
+
+/// A kept variant in the window of `prune_ld`.
+struct PruneVariant {
+    var_idx: usize,
+    chrom: String,
+    id: String,
+    masks: GenotypeMasks,
+    maf: f64,
+    pruned: bool,
+}
+
+impl LdWindow {
+    /// Whether `later` (read after `earlier`) is within the window of
+    /// `earlier`.
+    fn contains(&self, earlier: &LdVariant, later: &LdVariant) -> bool {
+        earlier.chrom == later.chrom
+            && earlier.pos.abs_diff(later.pos) <= self.max_bp
+            && later.kept_idx - earlier.kept_idx <= self.max_variants
+    }
+}
+
+impl Pfile {
+    /// Computes the r² and D′ between pairs of kept variants within `window`,
+    /// over the kept samples, writing the pairs with an r² of at least
+    /// `r2_min` to `{out_prefix}.vcor` (with the column names of plink2's
+    /// `--r2-unphased`).
+    ///
+    /// All pairs in the window are compared, unless an `index_var` ID is
+    /// passed, in which case only the pairs including it are.
+    pub fn output_ld(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        window: LdWindow,
+        index_var: Option<String>,
+        r2_min: f64,
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        let [chrom_idx, pos_idx, id_idx] = ["CHROM", "POS", "ID"].map(|name| {
+            pvar_headers
+                .iter()
+                .position(|col| col == name)
+                .unwrap_or_else(|| panic!("{} not among the headers of {}", name, self.pvar_path()))
+        });
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        let vcor = File::create(format!("{}.vcor", out_prefix))?;
+        let mut vcor_writer = BufWriter::new(vcor);
+        writeln!(
+            vcor_writer,
+            "#CHROM_A\tPOS_A\tID_A\tCHROM_B\tPOS_B\tID_B\tUNPHASED_R2\tD'"
+        )?;
+        let mut write_pair = |var_a: &LdVariant, var_b: &LdVariant| -> io::Result<()> {
+            match var_a.masks.ld(&var_b.masks) {
+                Some((r2, d_prime)) if r2 >= r2_min => writeln!(
+                    vcor_writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    var_a.chrom, var_a.pos, var_a.id, var_b.chrom, var_b.pos, var_b.id, r2, d_prime
+                ),
+                _ => Ok(()),
+            }
+        };
+
+        // the variants are compared as they are read, against the earlier
+        // variants still in their window
+        let mut window_vars: VecDeque<LdVariant> = VecDeque::new();
+        let mut index: Option<LdVariant> = None;
+        let mut record_reader = self.record_reader()?;
+        for (kept_idx, var_idx_rcd) in var_idx_rcds.enumerate() {
+            let (var_idx, var_rcd) = var_idx_rcd?;
+            let pos = var_rcd[pos_idx].parse().unwrap_or_else(|_| {
+                panic!("invalid POS {} in {}", &var_rcd[pos_idx], self.pvar_path())
+            });
+            let record_buf = record_reader.record(var_idx)?;
+            let var = LdVariant {
+                kept_idx,
+                chrom: var_rcd[chrom_idx].to_string(),
+                pos,
+                id: var_rcd[id_idx].to_string(),
+                masks: GenotypeMasks::from_record(
+                    record_buf,
+                    &sam_idx_rcds,
+                    self.num_samples as usize,
+                ),
+            };
+
+            if let Some(index) = index.as_ref() {
+                // past the index variant, we only compare against it
+                if !window.contains(index, &var) {
+                    break;
+                }
+                write_pair(index, &var)?;
+                continue;
+            }
+            while window_vars
+                .front()
+                .is_some_and(|earlier| !window.contains(earlier, &var))
+            {
+                window_vars.pop_front();
+            }
+            match index_var.as_ref() {
+                Some(index_var) if *index_var == var.id => {
+                    for earlier in window_vars.drain(..) {
+                        write_pair(&earlier, &var)?;
+                    }
+                    index = Some(var);
+                }
+                Some(_) => window_vars.push_back(var),
+                None => {
+                    for earlier in window_vars.iter() {
+                        write_pair(earlier, &var)?;
+                    }
+                    window_vars.push_back(var);
+                }
+            }
+        }
+
+        if let Some(index_var) = index_var.filter(|_| index.is_none()) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} not among the kept variants", index_var),
+            )
+            .into());
+        }
+        vcor_writer.flush()?;
+        Ok(())
+    }
+
+    /// Prunes the kept variants by LD over the kept samples, like plink2's
+    /// `--indep-pairwise`, returning which variants (by index) remain.
+    ///
+    /// The window moves along each chromosome `window.step` variants at a
+    /// time. Within it, of every pair of remaining variants with an r² above
+    /// `r2_max` the one with the lower minor allele frequency (or the later
+    /// one, on ties) is pruned. The IDs of the remaining and pruned variants
+    /// are written to `{out_prefix}.prune.in` and `{out_prefix}.prune.out`.
+    pub fn prune_ld(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        window: PruneWindow,
+        r2_max: f64,
+        out_prefix: &str,
+    ) -> csv::Result<Vec<bool>> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        let [chrom_idx, id_idx] = ["CHROM", "ID"].map(|name| {
+            pvar_headers
+                .iter()
+                .position(|col| col == name)
+                .unwrap_or_else(|| panic!("{} not among the headers of {}", name, self.pvar_path()))
+        });
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        let mut record_reader = self.record_reader()?;
+        let num_samples = self.num_samples as usize;
+        let mut vars = var_idx_rcds
+            .map(|var_idx_rcd| {
+                let (var_idx, var_rcd) = var_idx_rcd?;
+                let record_buf = record_reader.record(var_idx)?;
+                let masks = GenotypeMasks::from_record(record_buf, &sam_idx_rcds, num_samples);
+                let maf = masks.maf();
+                csv::Result::Ok(PruneVariant {
+                    var_idx,
+                    chrom: var_rcd[chrom_idx].to_string(),
+                    id: var_rcd[id_idx].to_string(),
+                    masks,
+                    maf,
+                    pruned: false,
+                })
+            })
+            .peekable();
+
+        let mut kept_variants = vec![false; self.num_variants as usize];
+        let create =
+            |ext: &str| File::create(format!("{}.{}", out_prefix, ext)).map(BufWriter::new);
+        let mut prune_in_writer = create("prune.in")?;
+        let mut prune_out_writer = create("prune.out")?;
+        let mut write_var = |var: PruneVariant| -> io::Result<()> {
+            kept_variants[var.var_idx] = !var.pruned;
+            if var.pruned {
+                writeln!(prune_out_writer, "{}", var.id)
+            } else {
+                writeln!(prune_in_writer, "{}", var.id)
+            }
+        };
+
+        let mut window_vars: VecDeque<PruneVariant> = VecDeque::with_capacity(window.size);
+        loop {
+            // fill the window, up to the end of the chromosome
+            while window_vars.len() < window.size {
+                let same_chrom = match (vars.peek(), window_vars.front()) {
+                    (None, _) => false,
+                    (Some(Ok(next)), Some(first)) => next.chrom == first.chrom,
+                    _ => true,
+                };
+                if !same_chrom {
+                    break;
+                }
+                window_vars.push_back(vars.next().unwrap()?);
+            }
+            if window_vars.is_empty() {
+                break;
+            }
+
+            for earlier_idx in 0..window_vars.len() {
+                for later_idx in earlier_idx + 1..window_vars.len() {
+                    let (earlier, later) = (&window_vars[earlier_idx], &window_vars[later_idx]);
+                    if earlier.pruned || later.pruned {
+                        continue;
+                    }
+                    let r2 = earlier.masks.ld(&later.masks).map_or(0.0, |(r2, _d)| r2);
+                    if r2 > r2_max {
+                        if earlier.maf < later.maf {
+                            window_vars[earlier_idx].pruned = true;
+                        } else {
+                            window_vars[later_idx].pruned = true;
+                        }
+                    }
+                }
+            }
+
+            // once the window reaches the end of the chromosome we are done
+            // with it, otherwise we slide it along
+            let chrom_done = match (vars.peek(), window_vars.front()) {
+                (Some(Ok(next)), Some(first)) => next.chrom != first.chrom,
+                (Some(Err(_)), _) => false,
+                _ => true,
+            };
+            let num_done = if chrom_done {
+                window_vars.len()
+            } else {
+                window.step.min(window_vars.len())
+            };
+            for var in window_vars.drain(..num_done) {
+                write_var(var)?;
+            }
+        }
+
+        prune_in_writer.flush()?;
+        prune_out_writer.flush()?;
+        Ok(kept_variants)
+    }
+}
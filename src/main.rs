@@ -1,10 +1,34 @@
+mod annotate;
+mod assoc;
 mod cli;
+mod concat;
+mod export;
+mod grm;
+mod kinship;
+mod ld;
+mod merge;
+mod pca;
 mod pfile;
+// Not wired up yet, kept around for the other storage modes.
+#[allow(dead_code)]
 mod pgen;
+mod pheno;
+mod records;
+mod score;
+mod split;
+mod stats;
+mod writer;
 
-use clap::Parser;
-use cli::{Cli, Commands};
+use annotate::Annotations;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser};
+use cli::{Cli, Commands, ExportFormat, GrmFormat, SplitBy};
+use export::{MatrixFormat, TableFormat};
+use ld::{LdWindow, PruneWindow};
 use pfile::Pfile;
+use pheno::PhenoTable;
+use score::ScoreColumns;
+use std::sync::Arc;
 
 // fn test_pgen() {
 //     let test_pgens = vec![
@@ -89,8 +113,37 @@ use pfile::Pfile;
 //     pfile.output_vcf(sample_ids, variant_ids);
 // }
 
+/// The global options applying to the pfiles opened by the subcommands.
+struct PfileOptions {
+    mmap: bool,
+    threads: usize,
+    pheno: Option<Arc<PhenoTable>>,
+    annotations: Option<Arc<Annotations>>,
+}
+
+impl PfileOptions {
+    /// Opens the pfile with the given prefix, with the global options.
+    fn open(&self, pfile_prefix: String) -> Pfile {
+        Pfile::from_prefix(pfile_prefix)
+            .with_mmap(self.mmap)
+            .with_threads(self.threads)
+            .with_pheno(self.pheno.clone())
+            .with_annotations(self.annotations.clone())
+    }
+}
+
 fn main() {
     let cli = Cli::parse();
+    let pfile_options = PfileOptions {
+        mmap: cli.mmap,
+        threads: cli.threads.get(),
+        pheno: cli
+            .pheno
+            .map(|pheno_path| Arc::new(PhenoTable::from_path(pheno_path).unwrap())),
+        annotations: cli
+            .annotations
+            .map(|annotations_path| Arc::new(Annotations::from_path(annotations_path).unwrap())),
+    };
     match cli.command {
         Commands::Query {
             pfile_prefix,
@@ -98,29 +151,361 @@ fn main() {
             query,
             query_samples,
         } => {
-            let pfile = Pfile::from_prefix(pfile_prefix);
+            let pfile = pfile_options.open(pfile_prefix);
             if query_samples {
-                let mut reader = pfile.psam_reader().unwrap();
+                pfile.query_samples(query, query_fstring).unwrap();
+            } else {
+                pfile.query_variants(query, query_fstring).unwrap();
+            }
+        }
+        Commands::Filter {
+            pfile_args,
+            out_file,
+            id_paste,
+            id_delim,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_file =
+                out_file.unwrap_or_else(|| format!("{}.pgen-rs.vcf", pfile.pfile_prefix).into());
+            let id_paste = id_paste
+                .into_iter()
+                .map(|id_part| match id_part {
+                    cli::IdPart::Fid => pfile::IdPart::Fid,
+                    cli::IdPart::MaybeFid => pfile::IdPart::MaybeFid,
+                    cli::IdPart::Iid => pfile::IdPart::Iid,
+                    cli::IdPart::Sid => pfile::IdPart::Sid,
+                    cli::IdPart::MaybeSid => pfile::IdPart::MaybeSid,
+                })
+                .collect::<Vec<pfile::IdPart>>();
+            pfile
+                .output_vcf(
+                    pfile_args.sam_query,
+                    pfile_args.var_query,
+                    out_file,
+                    &id_paste,
+                    id_delim,
+                )
+                .unwrap();
+        }
+        Commands::Export {
+            pfile_args,
+            format,
+            sample_major,
+            long,
+            out_args,
+        } => {
+            let matrix_format = matches!(format, ExportFormat::Npy | ExportFormat::Tsv);
+            if long && matrix_format {
+                Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--long only applies to --format parquet and arrow",
+                    )
+                    .exit();
+            }
+            if sample_major && !matrix_format {
+                Cli::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        "--sample-major only applies to --format npy and tsv",
+                    )
+                    .exit();
+            }
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            match format {
+                ExportFormat::Npy => pfile
+                    .output_matrix(
+                        pfile_args.sam_query,
+                        pfile_args.var_query,
+                        out_prefix,
+                        MatrixFormat::Npy,
+                        sample_major,
+                    )
+                    .unwrap(),
+                ExportFormat::Tsv => pfile
+                    .output_matrix(
+                        pfile_args.sam_query,
+                        pfile_args.var_query,
+                        out_prefix,
+                        MatrixFormat::Tsv,
+                        sample_major,
+                    )
+                    .unwrap(),
+                ExportFormat::Parquet => pfile
+                    .output_tables(
+                        pfile_args.sam_query,
+                        pfile_args.var_query,
+                        out_prefix,
+                        TableFormat::Parquet,
+                        long,
+                    )
+                    .unwrap(),
+                ExportFormat::Arrow => pfile
+                    .output_tables(
+                        pfile_args.sam_query,
+                        pfile_args.var_query,
+                        out_prefix,
+                        TableFormat::Arrow,
+                        long,
+                    )
+                    .unwrap(),
+            }
+        }
+        Commands::Stats {
+            pfile_args,
+            samples,
+            hwe,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            if samples {
                 pfile
-                    .query_metadata(&mut reader, query, query_fstring)
+                    .output_sample_stats(pfile_args.sam_query, pfile_args.var_query, out_prefix)
                     .unwrap();
             } else {
-                let mut reader = pfile.pvar_reader().unwrap();
                 pfile
-                    .query_metadata(&mut reader, query, query_fstring)
+                    .output_variant_stats(
+                        pfile_args.sam_query,
+                        pfile_args.var_query,
+                        hwe,
+                        out_prefix,
+                    )
                     .unwrap();
             }
         }
-        Commands::Filter {
-            pfile_prefix,
+        Commands::Ld {
+            pfile_args,
+            window_bp,
+            window_variants,
+            index_var,
+            r2_min,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            let window = LdWindow {
+                max_bp: window_bp,
+                max_variants: window_variants,
+            };
+            pfile
+                .output_ld(
+                    pfile_args.sam_query,
+                    pfile_args.var_query,
+                    window,
+                    index_var,
+                    r2_min,
+                    out_prefix,
+                )
+                .unwrap();
+        }
+        Commands::Prune {
+            pfile_args,
+            window,
+            step,
+            r2,
+            vcf,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            let window = PruneWindow {
+                size: window.get(),
+                step: step.get(),
+            };
+            let kept_variants = pfile
+                .prune_ld(
+                    pfile_args.sam_query.clone(),
+                    pfile_args.var_query.clone(),
+                    window,
+                    r2,
+                    &out_prefix,
+                )
+                .unwrap();
+            if vcf {
+                pfile
+                    .with_kept_variants(kept_variants)
+                    .output_vcf(
+                        pfile_args.sam_query,
+                        pfile_args.var_query,
+                        format!("{}.vcf", out_prefix).into(),
+                        &pfile::DEFAULT_ID_PASTE,
+                        pfile::DEFAULT_ID_DELIM,
+                    )
+                    .unwrap();
+            }
+        }
+        Commands::Grm {
+            pfile_args,
+            format,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            let format = match format {
+                GrmFormat::Gcta => grm::GrmFormat::Gcta,
+                GrmFormat::Rel => grm::GrmFormat::Rel,
+            };
+            pfile
+                .output_grm(
+                    pfile_args.sam_query,
+                    pfile_args.var_query,
+                    out_prefix,
+                    format,
+                )
+                .unwrap();
+        }
+        Commands::Pca {
+            pfile_args,
+            num_pcs,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            pfile
+                .output_pca(
+                    pfile_args.sam_query,
+                    pfile_args.var_query,
+                    num_pcs,
+                    out_prefix,
+                )
+                .unwrap();
+        }
+        Commands::Kinship {
+            pfile_args,
+            min_kinship,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            pfile
+                .output_kinship(
+                    pfile_args.sam_query,
+                    pfile_args.var_query,
+                    min_kinship,
+                    out_prefix,
+                )
+                .unwrap();
+        }
+        Commands::Assoc {
+            pfile_args,
+            pheno_name,
+            covar,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            pfile
+                .output_assoc(
+                    pfile_args.sam_query,
+                    pfile_args.var_query,
+                    &pheno_name,
+                    &covar,
+                    out_prefix,
+                )
+                .unwrap();
+        }
+        Commands::Score {
+            pfile_args,
+            score_file,
+            id_col,
+            allele_col,
+            weight_col,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            let columns = ScoreColumns {
+                id: id_col,
+                allele: allele_col,
+                weights: weight_col,
+            };
+            pfile
+                .output_score(
+                    pfile_args.sam_query,
+                    pfile_args.var_query,
+                    &score_file,
+                    &columns,
+                    out_prefix,
+                )
+                .unwrap();
+        }
+        Commands::Annotate {
+            pfile_args,
+            vcf,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            if vcf {
+                pfile
+                    .output_vcf(
+                        pfile_args.sam_query,
+                        pfile_args.var_query,
+                        format!("{}.vcf", out_prefix).into(),
+                        &pfile::DEFAULT_ID_PASTE,
+                        pfile::DEFAULT_ID_DELIM,
+                    )
+                    .unwrap();
+            } else {
+                pfile
+                    .output_annotated_pfile(pfile_args.sam_query, pfile_args.var_query, out_prefix)
+                    .unwrap();
+            }
+        }
+        Commands::Concat {
+            pfile_prefixes,
             var_query,
             sam_query,
-            out_file,
+            vcf,
+            out_prefix,
         } => {
-            let pfile = Pfile::from_prefix(pfile_prefix);
-            let out_file =
-                out_file.unwrap_or_else(|| format!("{}.pgen-rs.vcf", pfile.pfile_prefix).into());
-            pfile.output_vcf(sam_query, var_query, out_file).unwrap();
+            let pfiles = pfile_prefixes
+                .into_iter()
+                .map(|pfile_prefix| pfile_options.open(pfile_prefix))
+                .collect::<Vec<Pfile>>();
+            let out_prefix =
+                out_prefix.unwrap_or_else(|| format!("{}.pgen-rs", pfiles[0].pfile_prefix));
+            concat::output_concat(&pfiles, sam_query, var_query, out_prefix, vcf).unwrap();
+        }
+        Commands::Merge {
+            pfile_prefixes,
+            var_query,
+            sam_query,
+            out_prefix,
+        } => {
+            let pfiles = pfile_prefixes
+                .into_iter()
+                .map(|pfile_prefix| pfile_options.open(pfile_prefix))
+                .collect::<Vec<Pfile>>();
+            let out_prefix =
+                out_prefix.unwrap_or_else(|| format!("{}.pgen-rs", pfiles[0].pfile_prefix));
+            merge::output_merge(&pfiles, sam_query, var_query, out_prefix).unwrap();
+        }
+        Commands::Split {
+            pfile_args,
+            by,
+            chunks,
+            vcf,
+            out_args,
+        } => {
+            let pfile = pfile_options.open(pfile_args.pfile_prefix);
+            let out_prefix = out_args.out_prefix_or_default(&pfile.pfile_prefix);
+            let groups = match (by, chunks) {
+                (Some(SplitBy::Chrom), _) => split::SplitGroups::Chrom,
+                (None, Some(chunks)) => split::SplitGroups::Chunks(chunks.get()),
+                (None, None) => unreachable!("clap requires --by or --chunks"),
+            };
+            pfile
+                .output_split(
+                    pfile_args.sam_query,
+                    pfile_args.var_query,
+                    groups,
+                    vcf,
+                    out_prefix,
+                )
+                .unwrap();
         }
     }
     // test_pfile2();
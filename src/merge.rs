@@ -0,0 +1,326 @@
+use crate::annotate::{normalise_chrom, VariantKey};
+use crate::pfile::{genotype_code, IdPart, Pfile, MISSING_PHENO_VALUE};
+use crate::writer::{union_header_lines, MetadataWriter, PgenWriter};
+use csv::StringRecord;
+use std::collections::HashMap;
+use std::io;
+
+/// The genotype code of a missing call.
+const MISSING_CODE: u8 = 0b11;
+
+/// The .psam columns naming samples, which read as `0` rather than `NA`
+/// for the samples of pfiles lacking them.
+const SAMPLE_NAME_COLUMNS: [&str; 2] = ["FID", "SID"];
+
+/// A kept variant of one of the merged pfiles.
+struct VariantSource {
+    pfile_idx: usize,
+    var_idx: usize,
+    /// Whether its REF and ALT alleles are those of the merged variant the
+    /// other way round, so that its genotype codes are flipped.
+    swapped: bool,
+}
+
+/// A variant of the merged pfile, with the .pvar record of the first pfile
+/// having it and the pfiles its genotypes come from.
+struct MergedVariant {
+    chrom_key: ChromKey,
+    pos: u64,
+    var_rcd: StringRecord,
+    sources: Vec<VariantSource>,
+}
+
+/// Orders chromosomes as plink2 does: the numbered ones first, then X, Y, XY
+/// and MT, then any others by name.
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+enum ChromKey {
+    Numbered(u64),
+    Named(String),
+}
+
+impl ChromKey {
+    fn new(chrom: &str) -> ChromKey {
+        let chrom = normalise_chrom(chrom);
+        let num = match chrom {
+            "X" => Some(23),
+            "Y" => Some(24),
+            "XY" => Some(25),
+            "MT" | "M" => Some(26),
+            _ => chrom.parse().ok(),
+        };
+        num.map_or_else(|| ChromKey::Named(chrom.to_string()), ChromKey::Numbered)
+    }
+}
+
+/// The union of the columns of the given headers, in order of appearance.
+fn union_columns(headers: &[StringRecord]) -> StringRecord {
+    let mut union = StringRecord::new();
+    for header in headers {
+        for col in header.iter() {
+            if !union.iter().any(|union_col| union_col == col) {
+                union.push_field(col);
+            }
+        }
+    }
+    union
+}
+
+/// The values of the `out_header` columns in a record with the given
+/// header, with `missing(col)` for the columns it lacks.
+fn map_record(
+    out_header: &StringRecord,
+    header: &StringRecord,
+    rcd: &StringRecord,
+    missing: impl Fn(&str) -> &'static str,
+) -> StringRecord {
+    out_header
+        .iter()
+        .map(|out_col| {
+            header
+                .iter()
+                .position(|col| col == out_col)
+                .and_then(|col_idx| rcd.get(col_idx))
+                .unwrap_or_else(|| missing(out_col))
+        })
+        .collect()
+}
+
+/// Sets the genotype code of the sample at `sam_idx` in a packed variant
+/// record.
+fn set_genotype_code(record_buf: &mut [u8], sam_idx: usize, code: u8) {
+    let shift = (sam_idx % 4) * 2;
+    record_buf[sam_idx / 4] =
+        (record_buf[sam_idx / 4] & !(MISSING_CODE << shift)) | (code << shift);
+}
+
+/// Merges the kept samples and variants of the pfiles into a single pfile
+/// `{out_prefix}.pgen`, `.psam` and `.pvar`, with the union of their samples
+/// (by `IID` and `SID`, and by `FID` too when both .psam files have it) and of
+/// their variants (by `CHROM`, ignoring a `chr` prefix, `POS`, `REF` and
+/// `ALT`). The samples come in order of appearance, and the variants sorted by
+/// chromosome (as in `ChromKey`) and position.
+///
+/// Variants whose REF and ALT alleles are swapped in a later pfile are
+/// matched, flipping its genotypes. Variants with the same key within a
+/// pfile are kept apart, the n-th of them matching the n-th of any other.
+/// Samples get missing genotypes for the variants of the pfiles they are not
+/// in, and the first non-missing call among the pfiles otherwise. The .psam
+/// and .pvar records are those of the first pfile having the sample or
+/// variant, with the columns of all of them (`NA`, or `0` for `FID` and `SID`,
+/// and `.` for the ones they lack), and the .pvar has the `##` header lines of
+/// all the .pvar files, each line (or contig, INFO field and so on) once.
+pub fn output_merge(
+    pfiles: &[Pfile],
+    sam_query: Option<String>,
+    var_query: Option<String>,
+    out_prefix: String,
+) -> csv::Result<()> {
+    let mut psam_headers = Vec::new();
+    let mut kept_samples = Vec::new();
+    for pfile in pfiles {
+        let mut psam_reader = pfile.psam_reader()?;
+        psam_headers.push(psam_reader.headers()?.clone());
+        kept_samples.push(pfile.filter_samples(&mut psam_reader, sam_query.clone())?);
+    }
+    let mut out_psam_headers = union_columns(&psam_headers);
+    // the FID column (if any) must come first
+    if let Some(fid_idx) = out_psam_headers.iter().position(|col| col == "FID") {
+        let mut cols: Vec<&str> = out_psam_headers.iter().collect();
+        cols.remove(fid_idx);
+        cols.insert(0, "FID");
+        out_psam_headers = StringRecord::from(cols);
+    }
+
+    let mut out_sam_rcds = Vec::new();
+    // the merged samples with each IID (and SID), with their FID if they
+    // come from a .psam with the column, and the last pfile matching them
+    let mut out_sam_idxs: HashMap<String, Vec<usize>> = HashMap::new();
+    let mut out_sam_fids: Vec<Option<String>> = Vec::new();
+    let mut out_sam_last_pfiles: Vec<usize> = Vec::new();
+    // for each pfile, the index of each kept sample in its .pgen and in the
+    // merged one
+    let mut sam_idx_maps = Vec::new();
+    for (pfile_idx, pfile) in pfiles.iter().enumerate() {
+        let sam_header = &psam_headers[pfile_idx];
+        let sam_idx_rcds = &kept_samples[pfile_idx];
+        let sam_rcd_fid_idx = sam_header.iter().position(|col| col == "FID");
+        let names =
+            pfile.sample_names(sam_header, sam_idx_rcds, &[IdPart::Iid, IdPart::Sid], '\t')?;
+        let mut sam_idx_map = Vec::new();
+        for ((sam_idx, sam_rcd), name) in std::iter::zip(sam_idx_rcds, names) {
+            let fid = sam_rcd_fid_idx.map(|fid_idx| &sam_rcd[fid_idx]);
+            // the FIDs only have to match when both .psam files have them,
+            // and each merged sample matches one sample of a pfile at most
+            let matched = out_sam_idxs.get(&name).and_then(|out_idxs| {
+                out_idxs.iter().copied().find(|out_idx| {
+                    let same_fid = match (fid, out_sam_fids[*out_idx].as_deref()) {
+                        (Some(fid), Some(out_fid)) => fid == out_fid,
+                        _ => true,
+                    };
+                    same_fid && out_sam_last_pfiles[*out_idx] != pfile_idx
+                })
+            });
+            let out_idx = match matched {
+                Some(out_idx) => out_idx,
+                None => {
+                    out_sam_rcds.push(map_record(&out_psam_headers, sam_header, sam_rcd, |col| {
+                        if SAMPLE_NAME_COLUMNS.contains(&col) {
+                            "0"
+                        } else {
+                            MISSING_PHENO_VALUE
+                        }
+                    }));
+                    out_sam_fids.push(fid.map(str::to_string));
+                    out_sam_last_pfiles.push(pfile_idx);
+                    out_sam_idxs
+                        .entry(name)
+                        .or_default()
+                        .push(out_sam_rcds.len() - 1);
+                    out_sam_rcds.len() - 1
+                }
+            };
+            out_sam_last_pfiles[out_idx] = pfile_idx;
+            sam_idx_map.push((*sam_idx, out_idx));
+        }
+        sam_idx_maps.push(sam_idx_map);
+    }
+
+    let pvar_headers = pfiles
+        .iter()
+        .map(|pfile| Ok(pfile.pvar_reader()?.headers()?.clone()))
+        .collect::<csv::Result<Vec<StringRecord>>>()?;
+    let out_pvar_headers = union_columns(&pvar_headers);
+    let mut merged_variants: Vec<MergedVariant> = Vec::new();
+    // the merged variants with each key, in order of appearance
+    let mut merged_idxs: HashMap<[String; 4], Vec<usize>> = HashMap::new();
+    for (pfile_idx, pfile) in pfiles.iter().enumerate() {
+        let pvar_header = &pvar_headers[pfile_idx];
+        let key_col_idxs = VariantKey::column_idxs(pvar_header);
+        if [0, 1, 3, 4]
+            .iter()
+            .any(|key_idx| key_col_idxs[*key_idx].is_none())
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} lacks one of the CHROM, POS, REF and ALT columns",
+                    pfile.pvar_path()
+                ),
+            )
+            .into());
+        }
+        let mut pvar_reader = pfile.pvar_reader()?;
+        let var_idx_rcds = pfile.filter_variants_iter(
+            &mut pvar_reader,
+            var_query.clone(),
+            &kept_samples[pfile_idx],
+        )?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, var_rcd) = var_idx_rcd?;
+            let key = VariantKey::from_record(&key_col_idxs, &var_rcd);
+            let chrom = normalise_chrom(key.chrom);
+            let alleles_key = [chrom, key.pos, key.ref_allele, key.alt_allele].map(str::to_string);
+            let swapped_key = [chrom, key.pos, key.alt_allele, key.ref_allele].map(str::to_string);
+            // a variant matches the first merged variant with its key which
+            // has none of this pfile yet, so that duplicates within a pfile
+            // stay apart (and match the duplicates of other pfiles in order)
+            let unmatched = |key: &[String; 4]| {
+                merged_idxs.get(key).and_then(|merged_idxs| {
+                    merged_idxs.iter().copied().find(|merged_idx| {
+                        merged_variants[*merged_idx]
+                            .sources
+                            .iter()
+                            .all(|source| source.pfile_idx != pfile_idx)
+                    })
+                })
+            };
+            let (merged_idx, swapped) = if let Some(merged_idx) = unmatched(&alleles_key) {
+                (merged_idx, false)
+            } else if let Some(merged_idx) = unmatched(&swapped_key) {
+                (merged_idx, true)
+            } else {
+                let pos = key.pos.parse().map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("invalid POS {} in {}", key.pos, pfile.pvar_path()),
+                    )
+                })?;
+                merged_variants.push(MergedVariant {
+                    chrom_key: ChromKey::new(chrom),
+                    pos,
+                    var_rcd: map_record(&out_pvar_headers, pvar_header, &var_rcd, |_col| "."),
+                    sources: Vec::new(),
+                });
+                merged_idxs
+                    .entry(alleles_key)
+                    .or_default()
+                    .push(merged_variants.len() - 1);
+                (merged_variants.len() - 1, false)
+            };
+            merged_variants[merged_idx].sources.push(VariantSource {
+                pfile_idx,
+                var_idx,
+                swapped,
+            });
+        }
+    }
+
+    // the inputs may each be sorted, but their variants only come in order
+    // of appearance, which the (stable) sort keeps for ties
+    merged_variants.sort_by(|a, b| (&a.chrom_key, a.pos).cmp(&(&b.chrom_key, b.pos)));
+
+    let mut psam_writer =
+        MetadataWriter::create(format!("{}.psam", out_prefix), "", &out_psam_headers)?;
+    for sam_rcd in out_sam_rcds.iter() {
+        psam_writer.write_record(sam_rcd)?;
+    }
+    psam_writer.finish()?;
+    let pvar_header_lines = union_header_lines(
+        &pfiles
+            .iter()
+            .map(|pfile| pfile.read_pvar_header())
+            .collect::<Vec<String>>(),
+    );
+    let mut pvar_writer = MetadataWriter::create(
+        format!("{}.pvar", out_prefix),
+        &pvar_header_lines,
+        &out_pvar_headers,
+    )?;
+    let num_out_samples = out_sam_rcds.len();
+    let mut pgen_writer =
+        PgenWriter::create(format!("{}.pgen", out_prefix), num_out_samples as u32)?;
+
+    let mut record_readers = pfiles
+        .iter()
+        .map(|pfile| pfile.record_reader())
+        .collect::<io::Result<Vec<_>>>()?;
+    let mut out_buf = vec![0u8; pgen_writer.record_size()];
+    for variant in merged_variants.iter() {
+        out_buf.fill(0);
+        for out_idx in 0..num_out_samples {
+            set_genotype_code(&mut out_buf, out_idx, MISSING_CODE);
+        }
+        for source in variant.sources.iter() {
+            let record_buf = record_readers[source.pfile_idx].record(source.var_idx)?;
+            for (sam_idx, out_idx) in sam_idx_maps[source.pfile_idx].iter() {
+                if genotype_code(&out_buf, *out_idx) != MISSING_CODE {
+                    continue;
+                }
+                let code = genotype_code(record_buf, *sam_idx);
+                // hom REF and hom ALT trade places, hets and missing calls
+                // stay as they are
+                let code = if source.swapped && code != MISSING_CODE {
+                    2 - code
+                } else {
+                    code
+                };
+                set_genotype_code(&mut out_buf, *out_idx, code);
+            }
+        }
+        pvar_writer.write_record(&variant.var_rcd)?;
+        pgen_writer.write_record(&out_buf)?;
+    }
+    pvar_writer.finish()?;
+    pgen_writer.finish()?;
+    Ok(())
+}
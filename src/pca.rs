@@ -0,0 +1,184 @@
+use crate::pfile::{genotype_code, Pfile};
+use crate::records::RecordReader;
+use crate::stats::GenotypeCounts;
+use csv::StringRecord;
+use nalgebra::{DMatrix, SymmetricEigen};
+use rand::rngs::StdRng;
+use rand::{RngExt, SeedableRng};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// Number of variants standardized at a time in each pass over the .pgen.
+const PCA_CHUNK_VARIANTS: usize = 256;
+
+/// Number of extra dimensions in the random subspace, which makes the top
+/// components converge faster.
+const PCA_OVERSAMPLING: usize = 10;
+
+/// Number of power iterations (each a pass over the .pgen) refining the
+/// random subspace.
+const PCA_ITERATIONS: usize = 10;
+
+/// Seed of the starting random subspace, so that runs are reproducible.
+const PCA_SEED: u64 = 0x5eed;
+
+/// The standardized genotype matrix of the kept variants and samples, which
+/// is never held in memory but read a chunk of variants at a time.
+struct StandardizedGenotypes<'a> {
+    record_reader: RecordReader,
+    var_idxs: Vec<usize>,
+    sam_idx_rcds: &'a [(usize, StringRecord)],
+    num_samples: usize,
+}
+
+impl StandardizedGenotypes<'_> {
+    /// Computes XᵀX `mat` in one pass over the .pgen, where X is the
+    /// variants × kept samples standardized genotype matrix, with missing
+    /// genotypes (mean imputed) as 0. Also returns the number of variants
+    /// used, leaving out the monomorphic ones.
+    fn gram_product(&mut self, mat: &DMatrix<f64>) -> io::Result<(DMatrix<f64>, usize)> {
+        let num_kept_samples = self.sam_idx_rcds.len();
+        let mut product = DMatrix::<f64>::zeros(num_kept_samples, mat.ncols());
+        let mut chunk = DMatrix::<f64>::zeros(PCA_CHUNK_VARIANTS, num_kept_samples);
+        let mut num_used_variants = 0;
+        let mut chunk_len = 0;
+        for (var_pos, var_idx) in self.var_idxs.iter().enumerate() {
+            let record_buf = self.record_reader.record(*var_idx)?;
+            let counts =
+                GenotypeCounts::from_record(record_buf, self.sam_idx_rcds, self.num_samples);
+            if let Some((mean, sd)) = counts.standardization() {
+                for (kept_idx, (sam_idx, _sam_rcd)) in self.sam_idx_rcds.iter().enumerate() {
+                    let code = genotype_code(record_buf, *sam_idx);
+                    chunk[(chunk_len, kept_idx)] = if code == 0b11 {
+                        0.0
+                    } else {
+                        (code as f64 - mean) / sd
+                    };
+                }
+                chunk_len += 1;
+                num_used_variants += 1;
+            }
+            if chunk_len == PCA_CHUNK_VARIANTS
+                || (var_pos + 1 == self.var_idxs.len() && chunk_len > 0)
+            {
+                let chunk = chunk.rows(0, chunk_len);
+                product += chunk.transpose() * (chunk * mat);
+                chunk_len = 0;
+            }
+        }
+        Ok((product, num_used_variants))
+    }
+}
+
+impl Pfile {
+    /// Computes the top `num_pcs` principal components of the kept samples
+    /// from the standardized genotypes of the kept variants, with missing
+    /// genotypes mean imputed and monomorphic variants skipped.
+    ///
+    /// The components are approximated by randomized subspace iteration: a
+    /// random subspace of the samples is repeatedly multiplied by XᵀX (one
+    /// pass over the .pgen each) and orthonormalized, and the components are
+    /// then found within it. Only a few columns per sample are kept in
+    /// memory, so this scales to many samples.
+    ///
+    /// Fails when no samples or no polymorphic variants are kept.
+    ///
+    /// Writes the components to `{out_prefix}.eigenvec` and the eigenvalues
+    /// (of the GRM, that is of XᵀX divided by the number of variants) to
+    /// `{out_prefix}.eigenval`, as plink2's `--pca` does.
+    pub fn output_pca(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        num_pcs: usize,
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_rcd_id_idx = self.sample_id_idx(&sam_header)?;
+        let sam_rcd_fid_idx = sam_header.iter().position(|col| col == "FID");
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+        let num_kept_samples = sam_idx_rcds.len();
+        if num_kept_samples == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no samples are kept, so there are no components to compute",
+            )
+            .into());
+        }
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let var_idxs = self
+            .filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?
+            .map(|var_idx_rcd| var_idx_rcd.map(|(var_idx, _var_rcd)| var_idx))
+            .collect::<csv::Result<Vec<usize>>>()?;
+        let mut genotypes = StandardizedGenotypes {
+            record_reader: self.record_reader()?,
+            var_idxs,
+            sam_idx_rcds: &sam_idx_rcds,
+            num_samples: self.num_samples as usize,
+        };
+
+        let num_pcs = num_pcs.min(num_kept_samples);
+        let subspace_dim = (num_pcs + PCA_OVERSAMPLING).min(num_kept_samples);
+        let mut rng = StdRng::seed_from_u64(PCA_SEED);
+        let mut subspace = DMatrix::<f64>::from_fn(num_kept_samples, subspace_dim, |_, _| {
+            rng.random_range(-1.0..1.0)
+        })
+        .qr()
+        .q();
+        for _ in 0..PCA_ITERATIONS {
+            let (product, num_used_variants) = genotypes.gram_product(&subspace)?;
+            if num_used_variants == 0 {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no polymorphic variants are kept, so there are no components to compute",
+                )
+                .into());
+            }
+            subspace = product.qr().q();
+        }
+
+        // the eigenvectors of XᵀX restricted to the subspace
+        let (product, num_used_variants) = genotypes.gram_product(&subspace)?;
+        let projected = subspace.transpose() * product;
+        let projected = (&projected + projected.transpose()) / 2.0;
+        let eigen = SymmetricEigen::new(projected);
+        let mut order: Vec<usize> = (0..eigen.eigenvalues.len()).collect();
+        order.sort_by(|a, b| eigen.eigenvalues[*b].total_cmp(&eigen.eigenvalues[*a]));
+        let order = &order[..num_pcs];
+        let pcs = &subspace * eigen.eigenvectors.select_columns(order);
+
+        let mut eigenvec_writer = BufWriter::new(File::create(format!("{}.eigenvec", out_prefix))?);
+        let pc_names = (1..=num_pcs)
+            .map(|pc| format!("\tPC{}", pc))
+            .collect::<String>();
+        if sam_rcd_fid_idx.is_some() {
+            writeln!(eigenvec_writer, "#FID\tIID{}", pc_names)?;
+        } else {
+            writeln!(eigenvec_writer, "#IID{}", pc_names)?;
+        }
+        for (kept_idx, (_sam_idx, sam_rcd)) in sam_idx_rcds.iter().enumerate() {
+            if let Some(fid_idx) = sam_rcd_fid_idx {
+                write!(eigenvec_writer, "{}\t", &sam_rcd[fid_idx])?;
+            }
+            write!(eigenvec_writer, "{}", &sam_rcd[sam_rcd_id_idx])?;
+            for pc in 0..num_pcs {
+                write!(eigenvec_writer, "\t{}", pcs[(kept_idx, pc)])?;
+            }
+            writeln!(eigenvec_writer)?;
+        }
+        eigenvec_writer.flush()?;
+
+        let mut eigenval_writer = BufWriter::new(File::create(format!("{}.eigenval", out_prefix))?);
+        for pc_idx in order {
+            writeln!(
+                eigenval_writer,
+                "{}",
+                eigen.eigenvalues[*pc_idx] / num_used_variants as f64
+            )?;
+        }
+        eigenval_writer.flush()?;
+        Ok(())
+    }
+}
@@ -1,25 +1,195 @@
 use csv::{Reader, ReaderBuilder, StringRecord};
 use evalexpr::{
-    eval_boolean_with_context, eval_string_with_context, ContextWithMutableVariables,
-    HashMapContext, Value,
+    build_operator_tree, eval_boolean_with_context, eval_string_with_context,
+    ContextWithMutableVariables, HashMapContext, Value,
 };
 use std::fs::File;
 use std::io;
-use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
-use std::path::PathBuf;
+use std::io::{BufRead, BufReader, BufWriter, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
 
-// use polars_core::prelude::*;
-// use polars_io::prelude::*;
+use crate::annotate::{Annotations, VariantKey, MISSING_ANNOTATION};
+use crate::pheno::PhenoTable;
+use crate::records::RecordReader;
+use crate::stats::GenotypeCounts;
 
-// use polars::prelude::*;
-// use polars::prelude::{CsvReader, DataFrame, SerReader};
-// use polars_io::RowIndex;
-// use polars::prelude::DataFrame;
+/// The variable holding the HWE exact test p-value of each variant in
+/// `--include-var` queries.
+const HWE_P_VAR: &str = "HWE_P";
+
+/// The value of the `--pheno` columns in `--include-sam` queries for samples
+/// missing from the file.
+pub const MISSING_PHENO_VALUE: &str = "NA";
+
+/// Number of variants rendered by each worker at a time when writing VCFs.
+const VCF_CHUNK_VARIANTS: usize = 1024;
+
+/// The VCF rendering (with its leading tab) of each 2-bit genotype code.
+const VCF_GENOTYPES: [&[u8; 4]; 4] = [b"\t0/0", b"\t0/1", b"\t1/1", b"\t./."];
+
+/// The VCF rendering of the four genotypes packed in every possible record
+/// byte, so that all samples can be rendered a byte at a time.
+const VCF_GENOTYPE_BYTES: [[u8; 16]; 256] = {
+    let mut table = [[0u8; 16]; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut in_byte_offset = 0;
+        while in_byte_offset < 4 {
+            let genotype = VCF_GENOTYPES[(byte >> (in_byte_offset * 2)) & 0b11];
+            let mut i = 0;
+            while i < 4 {
+                table[byte][in_byte_offset * 4 + i] = genotype[i];
+                i += 1;
+            }
+            in_byte_offset += 1;
+        }
+        byte += 1;
+    }
+    table
+};
+
+/// The fixed columns of a VCF body line, before FORMAT and the genotypes.
+const VCF_COLUMNS: [&str; 8] = ["CHROM", "POS", "ID", "REF", "ALT", "QUAL", "FILTER", "INFO"];
+
+/// The index among the .pvar columns of each of the `VCF_COLUMNS`, if
+/// present, as the .pvar may lack some of them (or order them differently).
+pub(crate) fn vcf_column_idxs(pvar_headers: &StringRecord) -> Vec<Option<usize>> {
+    VCF_COLUMNS
+        .iter()
+        .map(|vcf_col| pvar_headers.iter().position(|col| col == *vcf_col))
+        .collect()
+}
+
+/// The .psam columns which can make up the names of the samples in VCFs,
+/// as in plink2's `--export id-paste`.
+#[derive(Clone, Copy)]
+pub enum IdPart {
+    Fid,
+    MaybeFid,
+    Iid,
+    Sid,
+    MaybeSid,
+}
+
+/// The sample names of the VCFs written without an `--id-paste`, the same
+/// as filter's default.
+pub const DEFAULT_ID_PASTE: [IdPart; 1] = [IdPart::Iid];
+
+/// The delimiter between the pasted parts of the default sample names.
+pub const DEFAULT_ID_DELIM: char = '_';
+
+/// The kinds of metadata files, which differ in their column names.
+#[derive(Clone, Copy)]
+enum MetadataKind {
+    Pvar,
+    Psam,
+}
+
+impl MetadataKind {
+    /// The column names with a special meaning in the plink2 spec.
+    fn known_columns(&self) -> &'static [&'static str] {
+        match self {
+            MetadataKind::Pvar => &[
+                "CHROM", "POS", "ID", "REF", "ALT", "QUAL", "FILTER", "INFO", "CM",
+            ],
+            MetadataKind::Psam => &["FID", "IID", "SID", "PAT", "MAT", "SEX"],
+        }
+    }
+
+    /// Whether the given line (the first one not starting with `##`) holds
+    /// the column names.
+    ///
+    /// .pvar header lines start with `#CHROM`, and .psam ones with `#FID` or
+    /// `#IID`, although we also take them without the `#`.
+    fn is_header_line(&self, line: &str) -> bool {
+        match self {
+            MetadataKind::Pvar => line.starts_with('#'),
+            MetadataKind::Psam => {
+                let first_col = line.split('\t').next().unwrap_or("");
+                let first_col = first_col.strip_prefix('#').unwrap_or(first_col);
+                line.starts_with('#')
+                    || first_col.eq_ignore_ascii_case("FID")
+                    || first_col.eq_ignore_ascii_case("IID")
+            }
+        }
+    }
+
+    /// The columns of a file without a header line, given the number of
+    /// fields in its records.
+    ///
+    /// Per the spec these follow the PLINK 1 .bim (with the CM column being
+    /// optional) and .fam orders.
+    fn default_columns(&self, num_cols: usize) -> Vec<&'static str> {
+        match self {
+            MetadataKind::Pvar if num_cols >= 6 => vec!["CHROM", "ID", "CM", "POS", "ALT", "REF"],
+            MetadataKind::Pvar => vec!["CHROM", "ID", "POS", "ALT", "REF"],
+            MetadataKind::Psam if num_cols >= 6 => {
+                vec!["FID", "IID", "PAT", "MAT", "SEX", "PHENO1"]
+            }
+            MetadataKind::Psam => vec!["FID", "IID", "PAT", "MAT", "SEX"],
+        }
+    }
+
+    /// Normalises a column name from a header line, so that expressions can
+    /// refer to the columns from the spec by their usual names, e.g. `Chrom`
+    /// becomes `CHROM`.
+    fn normalise_column(&self, col: &str) -> String {
+        let col = col.trim();
+        self.known_columns()
+            .iter()
+            .find(|known_col| known_col.eq_ignore_ascii_case(col))
+            .map_or_else(|| col.to_string(), |known_col| known_col.to_string())
+    }
+}
+
+/// A csv reader of the records of a (possibly compressed) .pvar or .psam.
+pub type MetadataReader = Reader<Box<dyn Read>>;
 
 pub struct Pfile {
     pub pfile_prefix: String,
     pub num_variants: u32,
     pub num_samples: u32,
+    /// Whether to memory map the .pgen instead of reading its records.
+    pub use_mmap: bool,
+    /// The number of threads rendering VCF lines.
+    pub threads: usize,
+    /// Which variants (by index) may be kept at all, on top of the
+    /// `--include-var` expressions, e.g. those left after LD pruning.
+    pub kept_variants: Option<Vec<bool>>,
+    /// Further sample columns from outside the .psam, e.g. phenotypes.
+    pub pheno: Option<Arc<PhenoTable>>,
+    /// Further variant columns from outside the .pvar, e.g. gene names.
+    pub annotations: Option<Arc<Annotations>>,
+}
+
+/// Extracts the 2-bit genotype code of the sample at `sam_idx` from a packed
+/// variant record.
+///
+/// The codes are 0b00 (hom ref), 0b01 (het), 0b10 (hom alt) and 0b11
+/// (missing).
+pub fn genotype_code(record_buf: &[u8], sam_idx: usize) -> u8 {
+    let host_byte = record_buf[sam_idx / 4];
+    let in_byte_offset = sam_idx % 4;
+    (host_byte >> (in_byte_offset * 2)) & 0b11
+}
+
+/// Percent-encodes the characters with a special meaning in VCF INFO values,
+/// keeping commas (which separate multiple values).
+fn encode_info_value(val: &str) -> String {
+    let mut encoded = String::with_capacity(val.len());
+    for c in val.chars() {
+        match c {
+            '%' => encoded.push_str("%25"),
+            ';' => encoded.push_str("%3B"),
+            '=' => encoded.push_str("%3D"),
+            ' ' => encoded.push_str("%20"),
+            '\t' => encoded.push_str("%09"),
+            _ => encoded.push(c),
+        }
+    }
+    encoded
 }
 
 impl Pfile {
@@ -28,11 +198,24 @@ impl Pfile {
     }
 
     pub fn psam_path(&self) -> String {
-        format!("{}.psam", self.pfile_prefix)
+        Pfile::metadata_path(&self.pfile_prefix, "psam")
     }
 
     pub fn pvar_path(&self) -> String {
-        format!("{}.pvar", self.pfile_prefix)
+        Pfile::metadata_path(&self.pfile_prefix, "pvar")
+    }
+
+    /// The path of the metadata file with the given extension. plink2 may
+    /// write it Zstandard-compressed (e.g. `.pvar.zst`), which we use when
+    /// the uncompressed file doesn't exist.
+    fn metadata_path(pfile_prefix: &str, ext: &str) -> String {
+        let path = format!("{}.{}", pfile_prefix, ext);
+        let zst_path = format!("{}.zst", path);
+        if !Path::new(&path).exists() && Path::new(&zst_path).exists() {
+            zst_path
+        } else {
+            path
+        }
     }
 
     pub fn from_prefix(pfile_prefix: String) -> Pfile {
@@ -72,17 +255,75 @@ impl Pfile {
             pfile_prefix,
             num_variants,
             num_samples,
+            use_mmap: false,
+            threads: 1,
+            kept_variants: None,
+            pheno: None,
+            annotations: None,
+        }
+    }
+
+    pub fn with_mmap(self, use_mmap: bool) -> Pfile {
+        Pfile { use_mmap, ..self }
+    }
+
+    pub fn with_threads(self, threads: usize) -> Pfile {
+        Pfile { threads, ..self }
+    }
+
+    pub fn with_kept_variants(self, kept_variants: Vec<bool>) -> Pfile {
+        Pfile {
+            kept_variants: Some(kept_variants),
+            ..self
         }
     }
 
-    pub fn query_metadata(
+    pub fn with_pheno(self, pheno: Option<Arc<PhenoTable>>) -> Pfile {
+        Pfile { pheno, ..self }
+    }
+
+    pub fn with_annotations(self, annotations: Option<Arc<Annotations>>) -> Pfile {
+        Pfile {
+            annotations,
+            ..self
+        }
+    }
+
+    /// Prints `f_string` for each .psam record matching the query, both of
+    /// which also have the columns of `pheno` (if any) as variables, as in
+    /// `filter_samples`.
+    pub fn query_samples(&self, query: Option<String>, f_string: String) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_rcd_id_idx = self.sample_id_idx(&sam_header)?;
+        let sam_rcd_fid_idx = sam_header.iter().position(|col| col == "FID");
+        self.query_metadata(&mut psam_reader, query, f_string, |sam_rcd, context| {
+            self.set_pheno_values(sam_rcd_fid_idx, sam_rcd_id_idx, sam_rcd, context)
+        })
+    }
+
+    /// Prints `f_string` for each .pvar record matching the query, both of
+    /// which also have the columns of `annotations` (if any) as variables, as
+    /// in `filter_variants_iter`.
+    pub fn query_variants(&self, query: Option<String>, f_string: String) -> csv::Result<()> {
+        let mut pvar_reader = self.pvar_reader()?;
+        let key_col_idxs = VariantKey::column_idxs(pvar_reader.headers()?);
+        self.query_metadata(&mut pvar_reader, query, f_string, |var_rcd, context| {
+            self.set_annotation_values(&key_col_idxs, var_rcd, context)
+        })
+    }
+
+    /// Prints `f_string` for each record matching the query, after `computed`
+    /// has set any further variables of the record in the context.
+    fn query_metadata(
         &self,
-        reader: &mut Reader<File>,
+        reader: &mut MetadataReader,
         query: Option<String>,
         f_string: String,
+        mut computed: impl FnMut(&StringRecord, &mut HashMapContext),
     ) -> csv::Result<()> {
         let headers: StringRecord = reader.headers()?.clone();
-        for (_idx, rcd) in reader.records().enumerate() {
+        for rcd in reader.records() {
             let rcd = rcd?;
             let mut context = HashMapContext::new();
             for (var, val) in std::iter::zip(&headers, &rcd) {
@@ -90,9 +331,10 @@ impl Pfile {
                     .set_value(var.to_string(), Value::String(val.to_string()))
                     .unwrap();
             }
-            let query_res = query.as_ref().map_or(true, |query| {
-                eval_boolean_with_context(query, &context).unwrap()
-            });
+            computed(&rcd, &mut context);
+            let query_res = query
+                .as_ref()
+                .is_none_or(|query| eval_boolean_with_context(query, &context).unwrap());
             if query_res {
                 let output = eval_string_with_context(&f_string, &context).unwrap();
                 println!("{}", output);
@@ -106,121 +348,368 @@ impl Pfile {
         sam_query: Option<String>,
         var_query: Option<String>,
         filename: PathBuf,
+        id_paste: &[IdPart],
+        id_delim: char,
     ) -> csv::Result<()> {
-        let (pvar_header, pvar_column_names) = self.read_pvar_header();
         let mut psam_reader = self.psam_reader()?;
-        let sam_header = psam_reader.headers()?;
-        // Index of the sample id in each sample record.
-        let sam_rcd_id_idx = sam_header
-            .iter()
-            .enumerate()
-            .find_map(|(idx, col)| {
-                // TODO: make this a constant
-                if col == "IID" {
-                    Some(idx)
-                } else {
-                    None
-                }
-            })
-            .unwrap_or_else(|| panic!("IID not among the headers of {}",
-                self.psam_path()));
-        let var_idx_rcds = self.filter_metadata(&mut self.pvar_reader()?, var_query)?;
-        let sam_idx_rcs = self.filter_metadata(&mut psam_reader, sam_query)?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_idx_rcs = self.filter_samples(&mut psam_reader, sam_query)?;
         // println!("filtered metadata");
-        let sam_ids = sam_idx_rcs
-            .iter()
-            .map(|(_idx, rcd)| rcd.get(sam_rcd_id_idx).unwrap().to_string())
-            .collect::<Vec<String>>()
+        let sam_ids = self
+            .sample_names(&sam_header, &sam_idx_rcs, id_paste, id_delim)?
             .join("\t");
 
         let vcf = File::create(filename)?;
         let mut vcf_writer = BufWriter::new(vcf);
-        // write the header
-        writeln!(vcf_writer, "##fileformat=VCFv4.2").unwrap();
-        writeln!(vcf_writer, "##source=pgen-rs").unwrap();
-        write!(vcf_writer, "{}", pvar_header).unwrap();
+        self.write_vcf_header(&mut vcf_writer, &self.read_pvar_header(), &sam_ids)?;
+        self.write_vcf_body(&mut vcf_writer, &sam_idx_rcs, var_query)?;
+        vcf_writer.flush()?;
+        Ok(())
+    }
 
-        // avoid push_str since it is slow
-        let pvar_column_names = pvar_column_names.trim().to_string();
-        write!(vcf_writer, "{}", pvar_column_names).unwrap();
-        writeln!(vcf_writer, "\tFORMAT\t{}", &sam_ids).unwrap();
+    /// Writes the VCF header lines, with the given `##` lines of the .pvar
+    /// (each ending in a newline), for samples with the given (tab-separated)
+    /// names.
+    pub(crate) fn write_vcf_header<W: Write>(
+        &self,
+        vcf_writer: &mut W,
+        pvar_header: &str,
+        sam_ids: &str,
+    ) -> io::Result<()> {
+        writeln!(vcf_writer, "##fileformat=VCFv4.2")?;
+        writeln!(vcf_writer, "##source=pgen-rs")?;
+        write!(vcf_writer, "{}", pvar_header)?;
+        if let Some(annotations) = self.annotations.as_ref() {
+            for col in annotations.columns.iter() {
+                writeln!(
+                    vcf_writer,
+                    "##INFO=<ID={},Number=.,Type=String,Description=\"{} from {}\">",
+                    col, col, annotations.source
+                )?;
+            }
+        }
+        writeln!(
+            vcf_writer,
+            "#{}\tFORMAT\t{}",
+            VCF_COLUMNS.join("\t"),
+            sam_ids
+        )
+    }
+
+    /// Writes the VCF body lines of the kept variants, with the genotypes of
+    /// the given samples, rendered by `self.threads` workers.
+    pub(crate) fn write_vcf_body<W: Write>(
+        &self,
+        vcf_writer: &mut W,
+        sam_idx_rcs: &[(usize, StringRecord)],
+        var_query: Option<String>,
+    ) -> csv::Result<()> {
+        let mut pvar_reader = self.pvar_reader()?;
+        let vcf_col_idxs = vcf_column_idxs(pvar_reader.headers()?);
 
         // now the fun part, write the actual data
-        let pgen = File::open(self.pgen_path()).unwrap();
-        // seems that BufReader makes things slower
-        // let mut pgen_reader = BufReader::new(pgen);
-        let mut pgen_reader = pgen;
-        // For writing the hot part of the loop (the body of the VCF) we will
-        // use BufWriter::write for performance reasons.
-        // println!("wrote header");
+        // The variants are filtered as the .pvar is read, a batch (one chunk
+        // per thread) at a time, so only the kept samples stay in memory.
+        // With multiple threads each worker renders a chunk of the batch into
+        // memory with its own reader, and we write the chunks out in order.
+        // seems that BufReader makes things slower, so unless memory mapped
+        // the records are read one at a time straight from the file
+        let mut record_readers = (0..self.threads)
+            .map(|_| self.record_reader())
+            .collect::<io::Result<Vec<RecordReader>>>()?;
+        let mut var_idx_rcds =
+            self.filter_variants_iter(&mut pvar_reader, var_query, sam_idx_rcs)?;
+        let mut batch = Vec::with_capacity(VCF_CHUNK_VARIANTS * self.threads);
+        loop {
+            batch.clear();
+            for var_idx_rcd in var_idx_rcds
+                .by_ref()
+                .take(VCF_CHUNK_VARIANTS * self.threads)
+            {
+                batch.push(var_idx_rcd?);
+            }
+            if batch.is_empty() {
+                break;
+            }
+            self.write_vcf_batch(
+                &batch,
+                &vcf_col_idxs,
+                sam_idx_rcs,
+                &mut record_readers,
+                vcf_writer,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The number of variants `write_vcf_batch` renders at once, a chunk
+    /// per thread.
+    pub(crate) fn vcf_batch_len(&self) -> usize {
+        VCF_CHUNK_VARIANTS * self.threads
+    }
+
+    /// Writes the VCF body lines of a batch of variants (of up to
+    /// `vcf_batch_len`), each of the `record_readers` (one per thread)
+    /// rendering a chunk of it.
+    pub(crate) fn write_vcf_batch<W: Write>(
+        &self,
+        batch: &[(usize, StringRecord)],
+        vcf_col_idxs: &[Option<usize>],
+        sam_idx_rcs: &[(usize, StringRecord)],
+        record_readers: &mut [RecordReader],
+        vcf_writer: &mut W,
+    ) -> io::Result<()> {
+        if self.threads <= 1 {
+            return self.write_vcf_lines(
+                batch,
+                vcf_col_idxs,
+                sam_idx_rcs,
+                &mut record_readers[0],
+                vcf_writer,
+            );
+        }
+
+        let chunks_lines = thread::scope(|scope| {
+            let workers = batch
+                .chunks(VCF_CHUNK_VARIANTS)
+                .zip(record_readers.iter_mut())
+                .map(|(chunk, record_reader)| {
+                    scope.spawn(move || {
+                        let mut lines = Vec::new();
+                        self.write_vcf_lines(
+                            chunk,
+                            vcf_col_idxs,
+                            sam_idx_rcs,
+                            record_reader,
+                            &mut lines,
+                        )
+                        .map(|_| lines)
+                    })
+                })
+                .collect::<Vec<_>>();
+            workers
+                .into_iter()
+                .map(|worker| worker.join().unwrap())
+                .collect::<Vec<io::Result<Vec<u8>>>>()
+        });
+        for lines in chunks_lines {
+            vcf_writer.write_all(&lines?)?;
+        }
+        Ok(())
+    }
+
+    /// Writes the VCF body lines of the given variants, with the genotypes of
+    /// the given samples. `vcf_col_idxs` holds the index in the variant
+    /// records of each of the `VCF_COLUMNS`, if present.
+    fn write_vcf_lines<W: Write>(
+        &self,
+        var_idx_rcds: &[(usize, StringRecord)],
+        vcf_col_idxs: &[Option<usize>],
+        sam_idx_rcs: &[(usize, StringRecord)],
+        record_reader: &mut RecordReader,
+        vcf_writer: &mut W,
+    ) -> io::Result<()> {
+        // For writing the hot part of the loop (the body of the VCF) we build
+        // each line in a reused buffer and hand it to the writer at once.
+        let all_samples = sam_idx_rcs.len() == self.num_samples as usize;
+        let mut line_buf = Vec::new();
         for (var_idx, var_rcd) in var_idx_rcds.iter() {
-            for col in var_rcd.iter() {
-                vcf_writer.write(col.as_bytes())?;
-                vcf_writer.write(b"\t")?;
+            line_buf.clear();
+            for col_idx in vcf_col_idxs {
+                let col = col_idx
+                    .and_then(|col_idx| var_rcd.get(col_idx))
+                    .unwrap_or(".");
+                line_buf.extend_from_slice(col.as_bytes());
+                line_buf.push(b'\t');
+            }
+            if let Some(annotations) = self.annotations.as_ref() {
+                self.append_info_annotations(annotations, vcf_col_idxs, var_rcd, &mut line_buf);
             }
-            vcf_writer.write("GT".as_bytes())?;
-            // pvar_line.push_str("\tGT");
-            // write!(vcf_writer, "\tGT").unwrap();
+            line_buf.extend_from_slice(b"GT");
 
-            let record_offset = 12 + (*var_idx as u32 * self.variant_record_size()) as u64;
-            // read the whole record to file
+            // read the whole record at once
             // this restricts the number of syscalls to |variants| instead of |variants| * |samples|
-            let mut record_buf = vec![0u8; self.variant_record_size() as usize];
-            pgen_reader.seek(SeekFrom::Start(record_offset)).unwrap();
-            pgen_reader.read_exact(&mut record_buf).unwrap();
-            for (sam_idx, _sam_rcd) in sam_idx_rcs.iter() {
-                let sample_offset = sam_idx / 4;
-                let host_byte = record_buf[sample_offset];
-                let in_byte_offset = sam_idx % 4;
-                let encoded_genotype = (host_byte >> (in_byte_offset * 2)) & 0b11;
-                //
-                let genotype = match encoded_genotype {
-                    0b00 => "0/0",
-                    0b01 => "0/1",
-                    0b10 => "1/1",
-                    0b11 => "./.",
-                    _ => panic!("unexpected genotype"),
-                };
-                // pvar_line.push_str("\t");
-                // pvar_line.push_str(genotype);
-                vcf_writer.write(b"\t")?;
-                vcf_writer.write(genotype.as_bytes())?;
+            let record_buf = record_reader.record(*var_idx)?;
+            if all_samples {
+                // every byte holds the genotypes of 4 consecutive samples, except
+                // possibly the last one which is padded with zeros
+                let num_samples = self.num_samples as usize;
+                let (full_bytes, last_byte) = record_buf.split_at(num_samples / 4);
+                for byte in full_bytes {
+                    line_buf.extend_from_slice(&VCF_GENOTYPE_BYTES[*byte as usize]);
+                }
+                if let Some(byte) = last_byte.first() {
+                    let rendered = &VCF_GENOTYPE_BYTES[*byte as usize];
+                    line_buf.extend_from_slice(&rendered[..(num_samples % 4) * 4]);
+                }
+            } else {
+                for (sam_idx, _sam_rcd) in sam_idx_rcs.iter() {
+                    let genotype = VCF_GENOTYPES[genotype_code(record_buf, *sam_idx) as usize];
+                    line_buf.extend_from_slice(genotype);
+                }
             }
-            // pvar_line.push_str("\n");
-            vcf_writer.write(b"\n")?;
-            // write!(vcf_writer, "{}", pvar_line).unwrap();
+            line_buf.push(b'\n');
+            vcf_writer.write_all(&line_buf)?;
         }
         Ok(())
     }
 
-    fn variant_record_size(&self) -> u32 {
-        let bit_size = self.num_samples * 2;
-        
-        (bit_size / 8) + if bit_size % 8 == 0 { 0 } else { 1 }
+    /// Adds the annotations of a variant to the INFO field at the end of its
+    /// VCF line so far (which ends in a tab).
+    fn append_info_annotations(
+        &self,
+        annotations: &Annotations,
+        vcf_col_idxs: &[Option<usize>],
+        var_rcd: &StringRecord,
+        line_buf: &mut Vec<u8>,
+    ) {
+        // the VCF columns start with those of the variant keys
+        let key_col_idxs = [0, 1, 2, 3, 4].map(|vcf_col| vcf_col_idxs[vcf_col]);
+        let Some(values) = annotations.values(&VariantKey::from_record(&key_col_idxs, var_rcd))
+        else {
+            return;
+        };
+        // INFO is the last of the VCF_COLUMNS
+        line_buf.pop();
+        let mut has_info = !line_buf.ends_with(b"\t.");
+        if !has_info {
+            line_buf.pop();
+        }
+        for (col, val) in std::iter::zip(&annotations.columns, &values) {
+            if val == MISSING_ANNOTATION || val.is_empty() {
+                continue;
+            }
+            if has_info {
+                line_buf.push(b';');
+            }
+            has_info = true;
+            line_buf.extend_from_slice(col.as_bytes());
+            line_buf.push(b'=');
+            line_buf.extend_from_slice(encode_info_value(val).as_bytes());
+        }
+        if !has_info {
+            line_buf.push(b'.');
+        }
+        line_buf.push(b'\t');
     }
 
-    fn read_pvar_header(&self) -> (String, String) {
-        let pvar = File::open(self.pvar_path()).unwrap();
-        let mut pvar_reader = BufReader::new(pvar);
-        // read all lines that start with # and store them in a vector
+    /// The size in bytes of each (fixed-width) variant record in the .pgen.
+    pub(crate) fn variant_record_size(&self) -> u32 {
+        (self.num_samples * 2).div_ceil(8)
+    }
+
+    /// Opens a reader of the variant records of the .pgen, memory mapped if
+    /// `use_mmap` is set.
+    pub(crate) fn record_reader(&self) -> io::Result<RecordReader> {
+        RecordReader::open(
+            self.pgen_path(),
+            self.num_variants as usize,
+            self.variant_record_size() as usize,
+            self.use_mmap,
+        )
+    }
+
+    /// Index of the variant id (ID) in each variant record.
+    pub(crate) fn variant_id_idx(&self, var_header: &StringRecord) -> usize {
+        var_header
+            .iter()
+            .position(|col| col == "ID")
+            .unwrap_or_else(|| panic!("ID not among the headers of {}", self.pvar_path()))
+    }
+
+    /// The names of the given samples, made by pasting together the parts
+    /// of their records in `id_paste` with `id_delim` in between.
+    ///
+    /// `IdPart::MaybeFid` and `IdPart::MaybeSid` are only included when the
+    /// column is present with some value other than `0` among the samples,
+    /// while a missing FID or SID column reads as `0` for `IdPart::Fid` and
+    /// `IdPart::Sid`.
+    pub(crate) fn sample_names(
+        &self,
+        sam_header: &StringRecord,
+        sam_idx_rcds: &[(usize, StringRecord)],
+        id_paste: &[IdPart],
+        id_delim: char,
+    ) -> csv::Result<Vec<String>> {
+        let col_idx = |name: &str| sam_header.iter().position(|col| col == name);
+        let any_non_zero = |col_idx: Option<usize>| {
+            col_idx.is_some_and(|col_idx| {
+                sam_idx_rcds
+                    .iter()
+                    .any(|(_idx, rcd)| rcd.get(col_idx).is_some_and(|val| val != "0"))
+            })
+        };
+        let (fid_idx, sid_idx) = (col_idx("FID"), col_idx("SID"));
+        let iid_idx = Some(self.sample_id_idx(sam_header)?);
+        // the columns (None for a missing one) pasted into each name
+        let name_col_idxs = id_paste
+            .iter()
+            .filter_map(|id_part| match id_part {
+                IdPart::Fid => Some(fid_idx),
+                IdPart::MaybeFid => any_non_zero(fid_idx).then_some(fid_idx),
+                IdPart::Iid => Some(iid_idx),
+                IdPart::Sid => Some(sid_idx),
+                IdPart::MaybeSid => any_non_zero(sid_idx).then_some(sid_idx),
+            })
+            .collect::<Vec<Option<usize>>>();
+
+        Ok(sam_idx_rcds
+            .iter()
+            .map(|(_idx, rcd)| {
+                name_col_idxs
+                    .iter()
+                    .map(|col_idx| col_idx.and_then(|col_idx| rcd.get(col_idx)).unwrap_or("0"))
+                    .collect::<Vec<&str>>()
+                    .join(&id_delim.to_string())
+            })
+            .collect())
+    }
+
+    /// Index of the sample id (IID) in each sample record.
+    pub(crate) fn sample_id_idx(&self, sam_header: &StringRecord) -> csv::Result<usize> {
+        sam_header
+            .iter()
+            .position(|col| col == "IID")
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("IID not among the headers of {}", self.psam_path()),
+                )
+                .into()
+            })
+    }
+
+    /// Reads the `##` header lines at the top of the .pvar, which carry
+    /// over to the header of VCFs.
+    pub(crate) fn read_pvar_header(&self) -> String {
+        let mut pvar_reader = Pfile::open_metadata_file(&self.pvar_path()).unwrap();
+        // read all lines that start with ## and store them in a vector
         let mut header_lines = Vec::new();
         loop {
             let mut buf = String::new();
             pvar_reader.read_line(&mut buf).unwrap();
-            if buf.starts_with('#') {
+            if buf.starts_with("##") {
                 header_lines.push(buf);
             } else {
                 break;
             }
         }
-        // the last line must be the column names
-        let header = header_lines.pop().unwrap();
-        // return the header comments and the column names
-        (header_lines.join(""), header)
+        header_lines.join("")
     }
 
-    /// Gives the offset to the start of the headers without the headers'
-    /// comment prefix.
+    /// Opens the metadata file at `path` for reading, transparently
+    /// decompressing it if it is a Zstandard-compressed `.zst` file.
+    fn open_metadata_file(path: &str) -> io::Result<Box<dyn BufRead>> {
+        let meta_file = File::open(path)?;
+        if path.ends_with(".zst") {
+            Ok(Box::new(BufReader::new(zstd::Decoder::new(meta_file)?)))
+        } else {
+            Ok(Box::new(BufReader::new(meta_file)))
+        }
+    }
+
+    /// Gives a csv reader of the records of the metadata file, with its
+    /// column names as headers.
     ///
     /// The pvar file will look like the following
     /// (psams look the same with different column types)
@@ -238,54 +727,70 @@ impl Pfile {
     ///
     ///     #CHROM ID POS ...
     ///
-    /// We want to seek to just after the # in that line so that we can
-    /// give the file reader to csv and it'll handle parsing everything.
+    /// That line may also be missing altogether, in which case the columns
+    /// are inferred from the number of fields of the first record (see
+    /// `MetadataKind::default_columns`). Either way the column names are
+    /// normalised with `MetadataKind::normalise_column`.
     ///
-    ///     #CHROM ID POS ...
-    ///      ^
-    ///      |
-    ///      start here
-    fn find_metadata_file_header_start(meta_file: String) -> io::Result<u64> {
-        let meta_file = File::open(meta_file)?;
-        let mut meta_raw_reader = BufReader::new(meta_file);
-        let mut prev_buf = String::new();
-        let mut buf = String::new();
+    /// Compressed files can't be seeked, so we read up to the first data line
+    /// and put it back in front of the rest of the file for csv to parse
+    /// everything.
+    fn metadata_file_reader(
+        file: String,
+        num_rows: usize,
+        kind: MetadataKind,
+    ) -> io::Result<MetadataReader> {
+        let mut meta_raw_reader = Pfile::open_metadata_file(&file)?;
+        let mut column_names = None;
         loop {
-            prev_buf = buf;
-            buf = String::new();
+            let mut buf = String::new();
             meta_raw_reader.read_line(&mut buf)?;
-            // We are reading the data now
-            if !buf.starts_with('#') {
-                let current_pos = meta_raw_reader.stream_position()?;
-                // The current line is not what we're looking for.
-                // The header is the previous line, but it is forced to start
-                // with a #.
-                // We want to rewind to just after that #.
-                let offset = (buf.len() + prev_buf.len()) as u64 - 1;
-                return Ok(current_pos - offset);
+            if buf.starts_with("##") {
+                continue;
             }
-        }
-    }
-
-    fn metadata_file_reader(file: String, num_rows: usize) -> io::Result<Reader<File>> {
-        let header_start = Pfile::find_metadata_file_header_start(file.clone())?;
-        let mut meta_file = File::open(file)?;
-        meta_file.seek(SeekFrom::Start(header_start))?;
+            let line = buf.trim_end_matches(['\r', '\n']);
+            if column_names.is_none() && kind.is_header_line(line) {
+                let line = line.strip_prefix('#').unwrap_or(line);
+                column_names = Some(
+                    line.split('\t')
+                        .map(|col| kind.normalise_column(col))
+                        .collect(),
+                );
+                continue;
+            }
+            // We are reading the data now
+            let column_names: Vec<String> = column_names.unwrap_or_else(|| {
+                let num_cols = if line.is_empty() {
+                    0
+                } else {
+                    line.split('\t').count()
+                };
+                let default_columns = kind.default_columns(num_cols).into_iter();
+                default_columns.map(|col| col.to_string()).collect()
+            });
+            let header = column_names.join("\t") + "\n";
+            let rest = Cursor::new(header + &buf).chain(meta_raw_reader);
 
-        let meta_reader = ReaderBuilder::new()
-            .delimiter(b'\t')
-            // per the spec, there are no comments
-            .buffer_capacity(num_rows)
-            // we seek to exactly where the headers start
-            .has_headers(true)
-            .from_reader(meta_file);
-        Ok(meta_reader)
+            let meta_reader = ReaderBuilder::new()
+                .delimiter(b'\t')
+                // per the spec, there are no comments
+                .buffer_capacity(num_rows)
+                // we start exactly where the (normalised) headers start
+                .has_headers(true)
+                .from_reader(Box::new(rest) as Box<dyn Read>);
+            return Ok(meta_reader);
+        }
     }
 
-    pub fn pvar_reader(&self) -> io::Result<Reader<File>> {
-        Pfile::metadata_file_reader(self.pvar_path(), self.num_variants as usize)
+    pub fn pvar_reader(&self) -> io::Result<MetadataReader> {
+        Pfile::metadata_file_reader(
+            self.pvar_path(),
+            self.num_variants as usize,
+            MetadataKind::Pvar,
+        )
     }
 
+    #[allow(dead_code)]
     pub fn filter_test(&self) -> io::Result<()> {
         let mut reader = self.pvar_reader()?;
         let headers: StringRecord = reader.headers()?.clone();
@@ -305,32 +810,173 @@ impl Pfile {
         Ok(())
     }
 
-    pub fn psam_reader(&self) -> io::Result<Reader<File>> {
-        Pfile::metadata_file_reader(self.psam_path(), self.num_samples as usize)
+    pub fn psam_reader(&self) -> io::Result<MetadataReader> {
+        Pfile::metadata_file_reader(
+            self.psam_path(),
+            self.num_samples as usize,
+            MetadataKind::Psam,
+        )
     }
 
-    fn filter_metadata(
+    /// Filters the .psam records by the query, which also has the columns of
+    /// `pheno` (if any) as variables, with `NA` for samples missing from it.
+    /// These take precedence over .psam columns of the same name.
+    pub(crate) fn filter_samples(
         &self,
-        meta_reader: &mut Reader<File>,
-        query: Option<String>,
+        psam_reader: &mut MetadataReader,
+        sam_query: Option<String>,
     ) -> csv::Result<Vec<(usize, StringRecord)>> {
-        let headers: StringRecord = meta_reader.headers()?.clone();
-        let mut kept_idx_vars = Vec::new();
-        for (idx, rcd) in meta_reader.records().enumerate() {
-            let rcd = rcd?;
-            let query_res = query.as_ref().map_or(true, |query| {
-                let mut context = HashMapContext::new();
-                for (var, val) in std::iter::zip(&headers, &rcd) {
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_rcd_id_idx = self.sample_id_idx(&sam_header)?;
+        let sam_rcd_fid_idx = sam_header.iter().position(|col| col == "FID");
+        self.filter_metadata_iter_with(
+            psam_reader,
+            sam_query,
+            |_sam_idx| true,
+            move |_sam_idx, sam_rcd, context| {
+                self.set_pheno_values(sam_rcd_fid_idx, sam_rcd_id_idx, sam_rcd, context);
+                Ok(())
+            },
+        )?
+        .collect()
+    }
+
+    /// Sets the `pheno` columns (if any) of a .psam record in the query
+    /// context, with `NA` for samples missing from it.
+    fn set_pheno_values(
+        &self,
+        sam_rcd_fid_idx: Option<usize>,
+        sam_rcd_id_idx: usize,
+        sam_rcd: &StringRecord,
+        context: &mut HashMapContext,
+    ) {
+        let Some(pheno) = self.pheno.as_ref() else {
+            return;
+        };
+        let fid = sam_rcd_fid_idx.map(|fid_idx| &sam_rcd[fid_idx]);
+        let row = pheno.get(fid, &sam_rcd[sam_rcd_id_idx]);
+        for (col_idx, col) in pheno.value_columns() {
+            let val = row.map_or(MISSING_PHENO_VALUE, |row| &row[col_idx]);
+            context
+                .set_value(col.to_string(), Value::String(val.to_string()))
+                .unwrap();
+        }
+    }
+
+    /// Filters the .pvar records by the query, which may also use statistics
+    /// computed from the genotypes of the kept samples (for now only
+    /// `HWE_P`). Their records are only read when the query mentions them.
+    ///
+    /// Variants left out of `kept_variants` are dropped whatever the query.
+    pub(crate) fn filter_variants_iter<'r>(
+        &'r self,
+        pvar_reader: &'r mut MetadataReader,
+        var_query: Option<String>,
+        sam_idx_rcds: &'r [(usize, StringRecord)],
+    ) -> csv::Result<impl Iterator<Item = csv::Result<(usize, StringRecord)>> + 'r> {
+        let mut record_reader = var_query
+            .as_ref()
+            .is_some_and(|query| {
+                build_operator_tree(query)
+                    .is_ok_and(|tree| tree.iter_variable_identifiers().any(|var| var == HWE_P_VAR))
+            })
+            .then(|| self.record_reader())
+            .transpose()?;
+        let num_samples = self.num_samples as usize;
+        let kept_variants = self.kept_variants.clone();
+        let key_col_idxs = VariantKey::column_idxs(pvar_reader.headers()?);
+        self.filter_metadata_iter_with(
+            pvar_reader,
+            var_query,
+            move |var_idx| {
+                kept_variants
+                    .as_ref()
+                    .is_none_or(|kept_variants| kept_variants[var_idx])
+            },
+            move |var_idx, var_rcd, context| {
+                self.set_annotation_values(&key_col_idxs, var_rcd, context);
+                if let Some(record_reader) = record_reader.as_mut() {
+                    let record_buf = record_reader.record(var_idx)?;
+                    let counts = GenotypeCounts::from_record(record_buf, sam_idx_rcds, num_samples);
                     context
-                        .set_value(var.to_string(), Value::String(val.to_string()))
+                        .set_value(HWE_P_VAR.to_string(), Value::Float(counts.hwe_p()))
                         .unwrap();
                 }
-                eval_boolean_with_context(query, &context).unwrap()
-            });
-            if query_res {
-                kept_idx_vars.push((idx, rcd));
-            }
+                Ok(())
+            },
+        )
+    }
+
+    /// Sets the `annotations` columns (if any) of a .pvar record in the query
+    /// context, with `.` for variants without a match.
+    fn set_annotation_values(
+        &self,
+        key_col_idxs: &[Option<usize>; 5],
+        var_rcd: &StringRecord,
+        context: &mut HashMapContext,
+    ) {
+        let Some(annotations) = self.annotations.as_ref() else {
+            return;
+        };
+        let values = annotations.values(&VariantKey::from_record(key_col_idxs, var_rcd));
+        for (col_idx, col) in annotations.columns.iter().enumerate() {
+            let val = values
+                .as_ref()
+                .map_or(MISSING_ANNOTATION, |values| &values[col_idx]);
+            context
+                .set_value(col.to_string(), Value::String(val.to_string()))
+                .unwrap();
         }
-        Ok(kept_idx_vars)
+    }
+
+    /// The collected counterpart of `filter_variants_iter`.
+    pub(crate) fn filter_variants(
+        &self,
+        pvar_reader: &mut MetadataReader,
+        var_query: Option<String>,
+        sam_idx_rcds: &[(usize, StringRecord)],
+    ) -> csv::Result<Vec<(usize, StringRecord)>> {
+        self.filter_variants_iter(pvar_reader, var_query, sam_idx_rcds)?
+            .collect()
+    }
+
+    /// Filters the records of a metadata file by the query, after `computed`
+    /// has set any further variables of each record in the query context.
+    /// Records for which `kept` is false are dropped before either.
+    fn filter_metadata_iter_with<'r>(
+        &self,
+        meta_reader: &'r mut MetadataReader,
+        query: Option<String>,
+        kept: impl Fn(usize) -> bool + 'r,
+        mut computed: impl FnMut(usize, &StringRecord, &mut HashMapContext) -> io::Result<()> + 'r,
+    ) -> csv::Result<impl Iterator<Item = csv::Result<(usize, StringRecord)>> + 'r> {
+        let headers: StringRecord = meta_reader.headers()?.clone();
+        let kept_idx_rcds = meta_reader
+            .records()
+            .enumerate()
+            .filter(move |(idx, _rcd)| kept(*idx))
+            .filter_map(move |(idx, rcd)| {
+                let rcd = match rcd {
+                    Ok(rcd) => rcd,
+                    Err(err) => return Some(Err(err)),
+                };
+                let query_res = match query.as_ref() {
+                    None => true,
+                    Some(query) => {
+                        let mut context = HashMapContext::new();
+                        for (var, val) in std::iter::zip(&headers, &rcd) {
+                            context
+                                .set_value(var.to_string(), Value::String(val.to_string()))
+                                .unwrap();
+                        }
+                        if let Err(err) = computed(idx, &rcd, &mut context) {
+                            return Some(Err(err.into()));
+                        }
+                        eval_boolean_with_context(query, &context).unwrap()
+                    }
+                };
+                query_res.then_some(Ok((idx, rcd)))
+            });
+        Ok(kept_idx_rcds)
     }
 }
@@ -57,12 +57,11 @@ impl Pgen {
         let provisional_ref_storage = (header_format_byte & (0b11 << 6)) >> 6;
         assert_eq!(provisional_ref_storage, 0b01);
 
-        let record_type_bits: u8;
-        match record_storage_mode / 4 {
-            0 => record_type_bits = 4,
-            1 => record_type_bits = 8,
+        let record_type_bits: u8 = match record_storage_mode / 4 {
+            0 => 4,
+            1 => 8,
             _ => panic!("invalid record storage mode"),
-        }
+        };
 
         let record_length_bytes: u8 = record_storage_mode % 4 + 1;
 
@@ -98,7 +97,7 @@ impl Pgen {
     }
 
     fn variant_block_count(&self) -> u64 {
-        (self.variant_count as u64 + Pgen::VARIANT_BLOCK_SIZE - 1) / Pgen::VARIANT_BLOCK_SIZE
+        (self.variant_count as u64).div_ceil(Pgen::VARIANT_BLOCK_SIZE)
     }
 
     fn variant_block_offsets_offset(&self) -> u64 {
@@ -119,11 +118,11 @@ impl Pgen {
 
         // the number of bytes needed to store all the record types
         let mut all_record_types_size = self.variant_count as u64 * self.record_type_bits as u64;
-        if all_record_types_size % 8 != 0 {
+        if !all_record_types_size.is_multiple_of(8) {
             all_record_types_size += 4;
         }
         // this should be the case since the record type bits can only be 4 or 8
-        assert!(all_record_types_size % 8 == 0);
+        assert!(all_record_types_size.is_multiple_of(8));
         all_record_types_size /= 8;
 
         // the number of bytes needed to store all the record lengths
@@ -205,10 +204,10 @@ impl Pgen {
 
             // the number of bytes needed to store all the record types
             let mut types_block_size = block_variant_count * self.record_type_bits as u64;
-            if types_block_size % 8 != 0 {
+            if !types_block_size.is_multiple_of(8) {
                 types_block_size += 4;
             }
-            assert!(types_block_size % 8 == 0);
+            assert!(types_block_size.is_multiple_of(8));
             types_block_size /= 8;
 
             let lengths_block_size = block_variant_count * self.record_length_bytes as u64;
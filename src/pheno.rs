@@ -0,0 +1,99 @@
+use csv::{ReaderBuilder, StringRecord};
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+
+/// Sample columns (e.g. phenotypes or covariates) from a tab-separated table
+/// outside the .psam, keyed by IID, or by FID and IID when both the table
+/// and the .psam have a FID column.
+///
+/// As in .psam files the header line may start with a `#`, e.g. `#FID IID`,
+/// and the FID and IID column names are taken in any case.
+pub struct PhenoTable {
+    pub headers: StringRecord,
+    iid_idx: usize,
+    fid_idx: Option<usize>,
+    rows: Vec<StringRecord>,
+    by_iid: HashMap<String, usize>,
+    by_fid_iid: HashMap<(String, String), usize>,
+}
+
+impl PhenoTable {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> csv::Result<PhenoTable> {
+        let mut reader = ReaderBuilder::new()
+            .delimiter(b'\t')
+            .from_path(path.as_ref())?;
+        let headers: StringRecord = reader
+            .headers()?
+            .iter()
+            .map(|col| {
+                let col = col.trim().trim_start_matches('#');
+                ["FID", "IID"]
+                    .into_iter()
+                    .find(|id_col| id_col.eq_ignore_ascii_case(col))
+                    .unwrap_or(col)
+            })
+            .collect();
+        let col_idx = |name: &str| headers.iter().position(|col| col == name);
+        let iid_idx = col_idx("IID").ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("IID not among the headers of {}", path.as_ref().display()),
+            )
+        })?;
+        let fid_idx = col_idx("FID");
+
+        let rows = reader
+            .records()
+            .collect::<csv::Result<Vec<StringRecord>>>()?;
+        let mut by_iid = HashMap::new();
+        let mut by_fid_iid = HashMap::new();
+        for (row_idx, row) in rows.iter().enumerate() {
+            let iid = row[iid_idx].to_string();
+            let duplicate = match fid_idx {
+                Some(fid_idx) => by_fid_iid
+                    .insert((row[fid_idx].to_string(), iid.clone()), row_idx)
+                    .is_some(),
+                None => by_iid.contains_key(&iid),
+            };
+            if duplicate {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("duplicate sample {} in {}", iid, path.as_ref().display()),
+                )
+                .into());
+            }
+            by_iid.insert(iid, row_idx);
+        }
+        Ok(PhenoTable {
+            headers,
+            iid_idx,
+            fid_idx,
+            rows,
+            by_iid,
+            by_fid_iid,
+        })
+    }
+
+    /// The index of the column with the given name, if any.
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.headers.iter().position(|col| col == name)
+    }
+
+    /// The indices and names of the columns other than FID and IID.
+    pub fn value_columns(&self) -> impl Iterator<Item = (usize, &str)> {
+        self.headers
+            .iter()
+            .enumerate()
+            .filter(|(col_idx, _col)| *col_idx != self.iid_idx && Some(*col_idx) != self.fid_idx)
+    }
+
+    /// The row of the sample with the given (FID and) IID, if any.
+    pub fn get(&self, fid: Option<&str>, iid: &str) -> Option<&StringRecord> {
+        let row_idx = match (fid, self.fid_idx) {
+            (Some(fid), Some(_)) => self.by_fid_iid.get(&(fid.to_string(), iid.to_string())),
+            _ => self.by_iid.get(iid),
+        };
+        row_idx.map(|row_idx| &self.rows[*row_idx])
+    }
+}
@@ -0,0 +1,100 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+/// Offset of the first variant record in a storage mode 0x02 .pgen.
+const RECORDS_OFFSET: usize = 12;
+
+/// Gives access to the fixed-width variant records of a .pgen.
+///
+/// The records are either sliced directly out of a memory map of the file, or
+/// read with a seek plus a `read_exact` each into an internal buffer.
+pub enum RecordReader {
+    Mmap {
+        mmap: Mmap,
+        record_size: usize,
+    },
+    File {
+        file: File,
+        record_size: usize,
+        buf: Vec<u8>,
+    },
+}
+
+impl RecordReader {
+    /// Opens the .pgen at `path` holding `num_variants` records of
+    /// `record_size` bytes each.
+    ///
+    /// When `use_mmap` is set we try to memory map the file, falling back to
+    /// plain reads (with a warning) if the platform or file doesn't allow it.
+    pub fn open(
+        path: String,
+        num_variants: usize,
+        record_size: usize,
+        use_mmap: bool,
+    ) -> io::Result<RecordReader> {
+        let file = File::open(&path)?;
+        if use_mmap {
+            // SAFETY: the mapping is read-only. Like every other reader of the
+            // .pgen we assume nobody truncates or rewrites it while we run.
+            match unsafe { Mmap::map(&file) } {
+                Ok(mmap) => {
+                    if mmap.len() < RECORDS_OFFSET + num_variants * record_size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("{} is too short for its variant records", path),
+                        ));
+                    }
+                    return Ok(RecordReader::Mmap { mmap, record_size });
+                }
+                Err(err) => {
+                    eprintln!(
+                        "could not memory map {} ({}), reading it instead",
+                        path, err
+                    )
+                }
+            }
+        }
+        Ok(RecordReader::File {
+            file,
+            record_size,
+            buf: vec![0u8; record_size],
+        })
+    }
+
+    /// The packed genotype record of the variant at `var_idx`.
+    pub fn record(&mut self, var_idx: usize) -> io::Result<&[u8]> {
+        let record_size = match self {
+            RecordReader::Mmap { record_size, .. } | RecordReader::File { record_size, .. } => {
+                *record_size
+            }
+        };
+        self.record_part(var_idx, 0, record_size)
+    }
+
+    /// `len` bytes of the packed genotype record of the variant at `var_idx`,
+    /// starting `byte_offset` bytes into the record.
+    pub fn record_part(
+        &mut self,
+        var_idx: usize,
+        byte_offset: usize,
+        len: usize,
+    ) -> io::Result<&[u8]> {
+        match self {
+            RecordReader::Mmap { mmap, record_size } => {
+                let start = RECORDS_OFFSET + var_idx * *record_size + byte_offset;
+                Ok(&mmap[start..start + len])
+            }
+            RecordReader::File {
+                file,
+                record_size,
+                buf,
+            } => {
+                let start = RECORDS_OFFSET + var_idx * *record_size + byte_offset;
+                file.seek(SeekFrom::Start(start as u64))?;
+                file.read_exact(&mut buf[..len])?;
+                Ok(&buf[..len])
+            }
+        }
+    }
+}
@@ -0,0 +1,200 @@
+use crate::pfile::{genotype_code, Pfile};
+use crate::stats::GenotypeCounts;
+use csv::{ReaderBuilder, StringRecord};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The names of the columns of a score file.
+pub struct ScoreColumns {
+    pub id: String,
+    pub allele: String,
+    /// One column per score.
+    pub weights: Vec<String>,
+}
+
+/// The effect allele and weights (one per score) of a variant in a score
+/// file.
+struct ScoreVariant {
+    allele: String,
+    weights: Vec<f64>,
+}
+
+/// The variants of a tab-separated score file, with a header line naming
+/// its columns, keyed by ID.
+struct ScoreFile {
+    variants: HashMap<String, ScoreVariant>,
+}
+
+impl ScoreFile {
+    fn from_path<P: AsRef<Path>>(path: P, columns: &ScoreColumns) -> csv::Result<ScoreFile> {
+        let path = path.as_ref();
+        let mut reader = ReaderBuilder::new().delimiter(b'\t').from_path(path)?;
+        let headers: StringRecord = reader
+            .headers()?
+            .iter()
+            .map(|col| col.trim().trim_start_matches('#'))
+            .collect();
+        let col_idx = |name: &str| {
+            headers.iter().position(|col| col == name).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} not among the headers of {}", name, path.display()),
+                )
+            })
+        };
+        let id_idx = col_idx(&columns.id)?;
+        let allele_idx = col_idx(&columns.allele)?;
+        let weight_idxs = columns
+            .weights
+            .iter()
+            .map(|name| col_idx(name))
+            .collect::<io::Result<Vec<usize>>>()?;
+
+        let mut variants = HashMap::new();
+        for row in reader.records() {
+            let row = row?;
+            let weights = weight_idxs
+                .iter()
+                .map(|weight_idx| {
+                    row[*weight_idx].parse().map_err(|_| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("invalid weight {} in {}", &row[*weight_idx], path.display()),
+                        )
+                    })
+                })
+                .collect::<io::Result<Vec<f64>>>()?;
+            let variant = ScoreVariant {
+                allele: row[allele_idx].to_string(),
+                weights,
+            };
+            if variants.insert(row[id_idx].to_string(), variant).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("duplicate variant {} in {}", &row[id_idx], path.display()),
+                )
+                .into());
+            }
+        }
+        Ok(ScoreFile { variants })
+    }
+}
+
+impl Pfile {
+    /// Computes polygenic scores for the kept samples from the kept variants
+    /// listed (by ID) in the `score_path` file, as the sums over the variants
+    /// of each weight column times the effect allele count. The effect
+    /// allele may be either the REF or the ALT allele of the variant, and
+    /// variants with any other effect allele are skipped. Missing genotypes
+    /// count as the mean effect allele count among the kept samples.
+    ///
+    /// Writes the scores to `{out_prefix}.sscore`, along with the number of
+    /// alleles called and the effect alleles among them for each sample, as
+    /// plink2's `--score` does (although with sums rather than averages).
+    pub fn output_score(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        score_path: &Path,
+        columns: &ScoreColumns,
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let score_file = ScoreFile::from_path(score_path, columns)?;
+        let num_scores = columns.weights.len();
+
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_rcd_id_idx = self.sample_id_idx(&sam_header)?;
+        let sam_rcd_fid_idx = sam_header.iter().position(|col| col == "FID");
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+        let num_kept_samples = sam_idx_rcds.len();
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        let var_rcd_id_idx = self.variant_id_idx(&pvar_headers);
+        let [ref_idx, alt_idx] =
+            ["REF", "ALT"].map(|name| pvar_headers.iter().position(|col| col == name));
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        // the scores are sample-major
+        let mut scores = vec![0f64; num_kept_samples * num_scores];
+        let mut allele_cts = vec![0u32; num_kept_samples];
+        let mut effect_allele_cts = vec![0u32; num_kept_samples];
+        let mut record_reader = self.record_reader()?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, var_rcd) = var_idx_rcd?;
+            let Some(variant) = score_file.variants.get(&var_rcd[var_rcd_id_idx]) else {
+                continue;
+            };
+            let is_allele = |col_idx: Option<usize>| {
+                col_idx
+                    .and_then(|col_idx| var_rcd.get(col_idx))
+                    .is_some_and(|allele| allele.eq_ignore_ascii_case(&variant.allele))
+            };
+            // whether the effect allele count is the ALT allele count or
+            // its complement
+            let is_alt = if is_allele(alt_idx) {
+                true
+            } else if is_allele(ref_idx) {
+                false
+            } else {
+                continue;
+            };
+            let record_buf = record_reader.record(var_idx)?;
+            let counts =
+                GenotypeCounts::from_record(record_buf, &sam_idx_rcds, self.num_samples as usize);
+            let Some(alt_freq) = counts.alt_freq() else {
+                continue;
+            };
+            let effect_ct = |alt_ct: f64| if is_alt { alt_ct } else { 2.0 - alt_ct };
+            let mean_effect_ct = effect_ct(2.0 * alt_freq);
+
+            for (kept_idx, (sam_idx, _sam_rcd)) in sam_idx_rcds.iter().enumerate() {
+                let code = genotype_code(record_buf, *sam_idx);
+                let dosage = if code == 0b11 {
+                    mean_effect_ct
+                } else {
+                    let dosage = effect_ct(code as f64);
+                    allele_cts[kept_idx] += 2;
+                    effect_allele_cts[kept_idx] += dosage as u32;
+                    dosage
+                };
+                let sample_scores = &mut scores[kept_idx * num_scores..(kept_idx + 1) * num_scores];
+                for (score, weight) in std::iter::zip(sample_scores, &variant.weights) {
+                    *score += dosage * weight;
+                }
+            }
+        }
+
+        let sscore = File::create(format!("{}.sscore", out_prefix))?;
+        let mut sscore_writer = BufWriter::new(sscore);
+        if sam_rcd_fid_idx.is_some() {
+            write!(sscore_writer, "#FID\tIID")?;
+        } else {
+            write!(sscore_writer, "#IID")?;
+        }
+        write!(sscore_writer, "\tALLELE_CT\tNAMED_ALLELE_DOSAGE_SUM")?;
+        for weight_col in columns.weights.iter() {
+            write!(sscore_writer, "\t{}_SUM", weight_col)?;
+        }
+        writeln!(sscore_writer)?;
+        for (kept_idx, (_sam_idx, sam_rcd)) in sam_idx_rcds.iter().enumerate() {
+            if let Some(fid_idx) = sam_rcd_fid_idx {
+                write!(sscore_writer, "{}\t", &sam_rcd[fid_idx])?;
+            }
+            write!(
+                sscore_writer,
+                "{}\t{}\t{}",
+                &sam_rcd[sam_rcd_id_idx], allele_cts[kept_idx], effect_allele_cts[kept_idx]
+            )?;
+            for score in &scores[kept_idx * num_scores..(kept_idx + 1) * num_scores] {
+                write!(sscore_writer, "\t{}", score)?;
+            }
+            writeln!(sscore_writer)?;
+        }
+        sscore_writer.flush()?;
+        Ok(())
+    }
+}
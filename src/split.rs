@@ -0,0 +1,232 @@
+use crate::pfile::{self, vcf_column_idxs, Pfile};
+use crate::records::RecordReader;
+use crate::writer::{header_line_key, PfileWriter};
+use csv::StringRecord;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+/// How the kept variants are split into groups.
+#[derive(Clone, Copy)]
+pub enum SplitGroups {
+    /// One group per chromosome, named after it.
+    Chrom,
+    /// This many groups of consecutive variants, as equal in size as
+    /// possible, named `chunk1`, `chunk2` and so on.
+    Chunks(usize),
+}
+
+/// The output of a group of variants, with the variants of a VCF batched to
+/// be rendered together.
+enum GroupWriter {
+    Pfile(PfileWriter),
+    Vcf(BufWriter<File>, Vec<(usize, StringRecord)>),
+}
+
+impl GroupWriter {
+    fn finish(self) -> io::Result<()> {
+        match self {
+            GroupWriter::Pfile(pfile_writer) => pfile_writer.finish(),
+            GroupWriter::Vcf(mut vcf_writer, _batch) => vcf_writer.flush(),
+        }
+    }
+}
+
+/// The chunk of the `var_num`-th of `num_variants` consecutive variants when
+/// split into `num_chunks` chunks, the first `num_variants % num_chunks` of
+/// them one variant larger than the others.
+fn chunk_idx(var_num: usize, num_variants: usize, num_chunks: usize) -> usize {
+    let (chunk_size, num_larger) = (num_variants / num_chunks, num_variants % num_chunks);
+    let larger_variants = num_larger * (chunk_size + 1);
+    if var_num < larger_variants {
+        var_num / (chunk_size + 1)
+    } else {
+        num_larger + (var_num - larger_variants) / chunk_size
+    }
+}
+
+/// The `##` header lines of the output of a chromosome, leaving out the
+/// `##contig` lines of the other chromosomes.
+fn chrom_header_lines(header_lines: &str, chrom: &str) -> String {
+    header_lines
+        .lines()
+        .filter(|line| match header_line_key(line) {
+            ("contig", Some(id)) => id == chrom,
+            _ => true,
+        })
+        .map(|line| format!("{}\n", line))
+        .collect()
+}
+
+impl Pfile {
+    /// Splits the kept variants into groups, writing those of each group
+    /// with the kept samples as a pfile `{out_prefix}.{group}.pgen`, `.psam`
+    /// and `.pvar`, or as `{out_prefix}.{group}.vcf` when `vcf` is set.
+    ///
+    /// The variants are streamed and the groups written one after the other,
+    /// so the variants of each chromosome must be contiguous in the .pvar.
+    /// The output of a chromosome keeps only its own `##contig` line.
+    pub fn output_split(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        groups: SplitGroups,
+        vcf: bool,
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_header = psam_reader.headers()?.clone();
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+        let sam_ids = self
+            .sample_names(
+                &sam_header,
+                &sam_idx_rcds,
+                &pfile::DEFAULT_ID_PASTE,
+                pfile::DEFAULT_ID_DELIM,
+            )?
+            .join("\t");
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        let pvar_header_lines = self.read_pvar_header();
+        let create_writer = |group_name: &str| -> io::Result<GroupWriter> {
+            let group_prefix = format!("{}.{}", out_prefix, group_name);
+            let header_lines = match groups {
+                SplitGroups::Chrom => chrom_header_lines(&pvar_header_lines, group_name),
+                SplitGroups::Chunks(_) => pvar_header_lines.clone(),
+            };
+            if vcf {
+                let vcf = File::create(format!("{}.vcf", group_prefix))?;
+                let mut vcf_writer = BufWriter::new(vcf);
+                self.write_vcf_header(&mut vcf_writer, &header_lines, &sam_ids)?;
+                Ok(GroupWriter::Vcf(vcf_writer, Vec::new()))
+            } else {
+                Ok(GroupWriter::Pfile(PfileWriter::create(
+                    &group_prefix,
+                    &sam_header,
+                    &sam_idx_rcds,
+                    self.num_samples as usize,
+                    &header_lines,
+                    &pvar_headers,
+                )?))
+            }
+        };
+
+        // The chunks need the number of kept variants up front, so only the
+        // kept variant indices are collected, and the records read again.
+        let chrom_idx = pvar_headers.iter().position(|col| col == "CHROM");
+        let mut num_kept_variants = 0;
+        let var_idx_rcds: Box<dyn Iterator<Item = csv::Result<(usize, StringRecord)>>> =
+            match groups {
+                SplitGroups::Chrom => {
+                    if chrom_idx.is_none() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("CHROM not among the headers of {}", self.pvar_path()),
+                        )
+                        .into());
+                    }
+                    Box::new(self.filter_variants_iter(
+                        &mut pvar_reader,
+                        var_query,
+                        &sam_idx_rcds,
+                    )?)
+                }
+                SplitGroups::Chunks(_) => {
+                    let mut kept = vec![false; self.num_variants as usize];
+                    for var_idx_rcd in
+                        self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?
+                    {
+                        kept[var_idx_rcd?.0] = true;
+                        num_kept_variants += 1;
+                    }
+                    Box::new(
+                        self.pvar_reader()?
+                            .into_records()
+                            .enumerate()
+                            .filter(move |(var_idx, _var_rcd)| kept[*var_idx])
+                            .map(|(var_idx, var_rcd)| var_rcd.map(|var_rcd| (var_idx, var_rcd))),
+                    )
+                }
+            };
+
+        let vcf_col_idxs = vcf_column_idxs(&pvar_headers);
+        // a record reader per thread rendering VCF lines
+        let mut record_readers = (0..self.threads)
+            .map(|_| self.record_reader())
+            .collect::<io::Result<Vec<RecordReader>>>()?;
+        let finish_group = |mut group_writer: GroupWriter, record_readers: &mut [RecordReader]| {
+            if let GroupWriter::Vcf(vcf_writer, batch) = &mut group_writer {
+                self.write_vcf_batch(
+                    batch,
+                    &vcf_col_idxs,
+                    &sam_idx_rcds,
+                    record_readers,
+                    vcf_writer,
+                )?;
+            }
+            group_writer.finish()
+        };
+        // the group being written, and the groups already written
+        let mut writer: Option<(String, GroupWriter)> = None;
+        let mut done_groups = HashSet::new();
+        for (var_num, var_idx_rcd) in var_idx_rcds.enumerate() {
+            let var_idx_rcd = var_idx_rcd?;
+            let group_name = match groups {
+                SplitGroups::Chrom => var_idx_rcd.1[chrom_idx.unwrap()].to_string(),
+                SplitGroups::Chunks(num_chunks) => format!(
+                    "chunk{}",
+                    chunk_idx(var_num, num_kept_variants, num_chunks) + 1
+                ),
+            };
+            if writer.as_ref().is_none_or(|(name, _)| *name != group_name) {
+                if let Some((name, group_writer)) = writer.take() {
+                    finish_group(group_writer, &mut record_readers)?;
+                    done_groups.insert(name);
+                }
+                if done_groups.contains(&group_name) {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!(
+                            "the variants of chromosome {} are not contiguous in {}",
+                            group_name,
+                            self.pvar_path()
+                        ),
+                    )
+                    .into());
+                }
+                writer = Some((group_name.clone(), create_writer(&group_name)?));
+            }
+            match &mut writer.as_mut().unwrap().1 {
+                GroupWriter::Pfile(pfile_writer) => {
+                    let (var_idx, var_rcd) = &var_idx_rcd;
+                    pfile_writer.write_variant(var_rcd, record_readers[0].record(*var_idx)?)?;
+                }
+                GroupWriter::Vcf(vcf_writer, batch) => {
+                    batch.push(var_idx_rcd);
+                    if batch.len() == self.vcf_batch_len() {
+                        self.write_vcf_batch(
+                            batch,
+                            &vcf_col_idxs,
+                            &sam_idx_rcds,
+                            &mut record_readers,
+                            vcf_writer,
+                        )?;
+                        batch.clear();
+                    }
+                }
+            }
+        }
+        if let Some((name, group_writer)) = writer.take() {
+            finish_group(group_writer, &mut record_readers)?;
+            done_groups.insert(name);
+        }
+        // the chunks left empty by fewer kept variants than chunks
+        if let SplitGroups::Chunks(num_chunks) = groups {
+            for chunk in done_groups.len() + 1..=num_chunks {
+                create_writer(&format!("chunk{}", chunk))?.finish()?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,397 @@
+use crate::pfile::{genotype_code, Pfile};
+use csv::StringRecord;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// The number of each genotype code (hom ref, het, hom alt, missing) packed
+/// in every possible record byte.
+const BYTE_GENOTYPE_COUNTS: [[u8; 4]; 256] = {
+    let mut table = [[0u8; 4]; 256];
+    let mut byte = 0;
+    while byte < 256 {
+        let mut in_byte_offset = 0;
+        while in_byte_offset < 4 {
+            table[byte][(byte >> (in_byte_offset * 2)) & 0b11] += 1;
+            in_byte_offset += 1;
+        }
+        byte += 1;
+    }
+    table
+};
+
+/// Counts of each genotype of a variant among the kept samples.
+#[derive(Clone, Copy, Default)]
+pub struct GenotypeCounts {
+    pub hom_ref: u32,
+    pub het: u32,
+    pub hom_alt: u32,
+    pub missing: u32,
+}
+
+impl GenotypeCounts {
+    /// Counts the genotypes of the given samples in a packed variant record.
+    ///
+    /// When all `num_samples` samples are kept the record is counted a byte
+    /// at a time, otherwise we look up each sample.
+    pub fn from_record(
+        record_buf: &[u8],
+        sam_idx_rcds: &[(usize, StringRecord)],
+        num_samples: usize,
+    ) -> GenotypeCounts {
+        let mut counts = [0u32; 4];
+        if sam_idx_rcds.len() == num_samples {
+            for byte in record_buf {
+                for (count, byte_count) in
+                    counts.iter_mut().zip(BYTE_GENOTYPE_COUNTS[*byte as usize])
+                {
+                    *count += byte_count as u32;
+                }
+            }
+            // the last byte is padded with (hom ref) zeros
+            counts[0] -= (record_buf.len() * 4 - num_samples) as u32;
+        } else {
+            for (sam_idx, _sam_rcd) in sam_idx_rcds.iter() {
+                counts[genotype_code(record_buf, *sam_idx) as usize] += 1;
+            }
+        }
+        GenotypeCounts {
+            hom_ref: counts[0],
+            het: counts[1],
+            hom_alt: counts[2],
+            missing: counts[3],
+        }
+    }
+
+    /// The number of non-missing genotypes.
+    pub fn called(&self) -> u32 {
+        self.hom_ref + self.het + self.hom_alt
+    }
+
+    /// The number of ALT alleles among the called genotypes.
+    pub fn alt_alleles(&self) -> u32 {
+        self.het + 2 * self.hom_alt
+    }
+
+    /// The number of alleles among the called genotypes.
+    pub fn alleles(&self) -> u32 {
+        2 * self.called()
+    }
+
+    /// The ALT allele frequency, if any genotype was called.
+    pub fn alt_freq(&self) -> Option<f64> {
+        (self.called() > 0).then(|| self.alt_alleles() as f64 / self.alleles() as f64)
+    }
+
+    /// The mean and standard deviation of the ALT allele count under
+    /// Hardy-Weinberg equilibrium given the ALT allele frequency, used to
+    /// standardize the genotypes. `None` if the variant is monomorphic (or
+    /// no genotype was called).
+    pub fn standardization(&self) -> Option<(f64, f64)> {
+        let alt_freq = self
+            .alt_freq()
+            .filter(|alt_freq| *alt_freq > 0.0 && *alt_freq < 1.0)?;
+        Some((2.0 * alt_freq, (2.0 * alt_freq * (1.0 - alt_freq)).sqrt()))
+    }
+
+    /// The p-value of the exact test for Hardy-Weinberg equilibrium of
+    /// Wigginton, Cutler and Abecasis (2005), or 1 if no genotype was called.
+    ///
+    /// Sums the probabilities, given the number of minor alleles, of every
+    /// het count at most as likely as the observed one.
+    pub fn hwe_p(&self) -> f64 {
+        let genotypes = self.called() as usize;
+        if genotypes == 0 {
+            return 1.0;
+        }
+        let obs_hets = self.het as usize;
+        let obs_homr = self.hom_ref.min(self.hom_alt) as usize;
+        let rare_copies = 2 * obs_homr + obs_hets;
+
+        // start from the most likely het count (which has the parity of the
+        // number of minor alleles) and work outwards in both directions
+        let mut het_probs = vec![0f64; rare_copies + 1];
+        let mut mid = rare_copies * (2 * genotypes - rare_copies) / (2 * genotypes);
+        if mid % 2 != rare_copies % 2 {
+            mid += 1;
+        }
+        het_probs[mid] = 1.0;
+        let mut sum = 1.0;
+
+        let mut curr_homr = (rare_copies - mid) / 2;
+        let mut curr_homc = genotypes - mid - curr_homr;
+        let mut curr_hets = mid;
+        while curr_hets > 1 {
+            het_probs[curr_hets - 2] = het_probs[curr_hets] * (curr_hets * (curr_hets - 1)) as f64
+                / (4.0 * (curr_homr + 1) as f64 * (curr_homc + 1) as f64);
+            sum += het_probs[curr_hets - 2];
+            curr_homr += 1;
+            curr_homc += 1;
+            curr_hets -= 2;
+        }
+
+        let mut curr_homr = (rare_copies - mid) / 2;
+        let mut curr_homc = genotypes - mid - curr_homr;
+        let mut curr_hets = mid;
+        while curr_hets + 2 <= rare_copies {
+            het_probs[curr_hets + 2] =
+                het_probs[curr_hets] * 4.0 * curr_homr as f64 * curr_homc as f64
+                    / ((curr_hets + 2) * (curr_hets + 1)) as f64;
+            sum += het_probs[curr_hets + 2];
+            curr_homr -= 1;
+            curr_homc -= 1;
+            curr_hets += 2;
+        }
+
+        let obs_prob = het_probs[obs_hets];
+        let p_hwe: f64 = het_probs
+            .iter()
+            .filter(|prob| **prob <= obs_prob)
+            .sum::<f64>()
+            / sum;
+        p_hwe.min(1.0)
+    }
+}
+
+/// Per-sample counts accumulated over the kept variants.
+#[derive(Clone, Copy, Default)]
+struct SampleCounts {
+    missing: u32,
+    het: u32,
+    hom_alt: u32,
+    /// The expected number of homozygous calls under Hardy-Weinberg
+    /// equilibrium, given the ALT allele frequency of each called variant.
+    expected_hom: f64,
+}
+
+/// Formats an optional statistic, writing NA (as plink2 does) if missing.
+pub fn fmt_stat(stat: Option<f64>) -> String {
+    stat.map_or_else(|| "NA".to_string(), |stat| stat.to_string())
+}
+
+impl Pfile {
+    /// Computes per-variant statistics over the kept samples, for each kept
+    /// variant, using the column names of the corresponding plink2 outputs:
+    ///
+    /// * `{out_prefix}.afreq`: ALT allele counts and frequencies (`--freq`).
+    /// * `{out_prefix}.vmiss`: missing call counts and rates (`--missing`).
+    /// * `{out_prefix}.gcount`: genotype counts (`--geno-counts`).
+    /// * `{out_prefix}.hardy`: observed and expected heterozygosity, and the
+    ///   HWE exact test p-value (`--hardy`), when `hwe` is set.
+    pub fn output_variant_stats(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        hwe: bool,
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+        let num_kept_samples = sam_idx_rcds.len();
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let pvar_headers = pvar_reader.headers()?.clone();
+        let [chrom_idx, id_idx, ref_idx, alt_idx] = ["CHROM", "ID", "REF", "ALT"]
+            .map(|name| pvar_headers.iter().position(|col| col == name));
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        let create =
+            |ext: &str| File::create(format!("{}.{}", out_prefix, ext)).map(BufWriter::new);
+        let mut afreq_writer = create("afreq")?;
+        let mut vmiss_writer = create("vmiss")?;
+        let mut gcount_writer = create("gcount")?;
+        writeln!(
+            afreq_writer,
+            "#CHROM\tID\tREF\tALT\tALT_CTS\tALT_FREQS\tOBS_CT"
+        )?;
+        writeln!(vmiss_writer, "#CHROM\tID\tMISSING_CT\tOBS_CT\tF_MISS")?;
+        writeln!(
+            gcount_writer,
+            "#CHROM\tID\tREF\tALT\tHOM_REF_CT\tHET_REF_ALT_CTS\tTWO_ALT_GENO_CTS\tMISSING_CT"
+        )?;
+        let mut hardy_writer = hwe.then(|| create("hardy")).transpose()?;
+        if let Some(hardy_writer) = hardy_writer.as_mut() {
+            writeln!(
+                hardy_writer,
+                "#CHROM\tID\tA1\tAX\tHOM_A1_CT\tHET_A1_CT\tTWO_AX_CT\tO(HET_A1)\tE(HET_A1)\tP"
+            )?;
+        }
+
+        let mut record_reader = self.record_reader()?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, var_rcd) = var_idx_rcd?;
+            let col = |col_idx: Option<usize>| {
+                col_idx
+                    .and_then(|col_idx| var_rcd.get(col_idx))
+                    .unwrap_or(".")
+            };
+            let (chrom, id, ref_allele, alt_allele) =
+                (col(chrom_idx), col(id_idx), col(ref_idx), col(alt_idx));
+
+            let record_buf = record_reader.record(var_idx)?;
+            let counts =
+                GenotypeCounts::from_record(record_buf, &sam_idx_rcds, self.num_samples as usize);
+            writeln!(
+                afreq_writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                chrom,
+                id,
+                ref_allele,
+                alt_allele,
+                counts.alt_alleles(),
+                fmt_stat(counts.alt_freq()),
+                counts.alleles()
+            )?;
+            let f_miss =
+                (num_kept_samples > 0).then(|| counts.missing as f64 / num_kept_samples as f64);
+            writeln!(
+                vmiss_writer,
+                "{}\t{}\t{}\t{}\t{}",
+                chrom,
+                id,
+                counts.missing,
+                num_kept_samples,
+                fmt_stat(f_miss)
+            )?;
+            writeln!(
+                gcount_writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                chrom,
+                id,
+                ref_allele,
+                alt_allele,
+                counts.hom_ref,
+                counts.het,
+                counts.hom_alt,
+                counts.missing
+            )?;
+            if let Some(hardy_writer) = hardy_writer.as_mut() {
+                let called = (counts.called() > 0).then_some(counts.called() as f64);
+                let o_het = called.map(|called| counts.het as f64 / called);
+                let e_het = counts
+                    .alt_freq()
+                    .map(|alt_freq| 2.0 * alt_freq * (1.0 - alt_freq));
+                writeln!(
+                    hardy_writer,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    chrom,
+                    id,
+                    ref_allele,
+                    alt_allele,
+                    counts.hom_ref,
+                    counts.het,
+                    counts.hom_alt,
+                    fmt_stat(o_het),
+                    fmt_stat(e_het),
+                    fmt_stat(called.map(|_| counts.hwe_p()))
+                )?;
+            }
+        }
+        for mut writer in [afreq_writer, vmiss_writer, gcount_writer]
+            .into_iter()
+            .chain(hardy_writer)
+        {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Computes per-sample statistics over the kept variants, for each kept
+    /// sample, keyed by IID:
+    ///
+    /// * `{out_prefix}.smiss`: missing call counts and rates (`--missing`).
+    /// * `{out_prefix}.het`: het and hom alt counts, along with the observed
+    ///   and expected homozygous counts and the method-of-moments inbreeding
+    ///   coefficient F (`--het`).
+    ///
+    /// The expected homozygous counts use the ALT allele frequency among the
+    /// kept samples, with the same small sample correction as plink2.
+    pub fn output_sample_stats(
+        &self,
+        sam_query: Option<String>,
+        var_query: Option<String>,
+        out_prefix: String,
+    ) -> csv::Result<()> {
+        let mut psam_reader = self.psam_reader()?;
+        let sam_rcd_id_idx = self.sample_id_idx(psam_reader.headers()?)?;
+        let sam_idx_rcds = self.filter_samples(&mut psam_reader, sam_query)?;
+
+        let mut pvar_reader = self.pvar_reader()?;
+        let var_idx_rcds = self.filter_variants_iter(&mut pvar_reader, var_query, &sam_idx_rcds)?;
+
+        let mut sam_counts = vec![SampleCounts::default(); sam_idx_rcds.len()];
+        let mut num_kept_variants = 0u32;
+        let mut record_reader = self.record_reader()?;
+        for var_idx_rcd in var_idx_rcds {
+            let (var_idx, _var_rcd) = var_idx_rcd?;
+            num_kept_variants += 1;
+            let record_buf = record_reader.record(var_idx)?;
+            let counts =
+                GenotypeCounts::from_record(record_buf, &sam_idx_rcds, self.num_samples as usize);
+            // variants with fewer than two called alleles carry no information
+            // on the expected heterozygosity
+            let expected_hom = match counts.alt_freq() {
+                Some(alt_freq) if counts.alleles() > 1 => {
+                    let alleles = counts.alleles() as f64;
+                    1.0 - 2.0 * alt_freq * (1.0 - alt_freq) * alleles / (alleles - 1.0)
+                }
+                _ => 1.0,
+            };
+            for ((sam_idx, _sam_rcd), sam_count) in sam_idx_rcds.iter().zip(sam_counts.iter_mut()) {
+                match genotype_code(record_buf, *sam_idx) {
+                    0b01 => sam_count.het += 1,
+                    0b10 => sam_count.hom_alt += 1,
+                    0b11 => {
+                        sam_count.missing += 1;
+                        continue;
+                    }
+                    _ => {}
+                }
+                sam_count.expected_hom += expected_hom;
+            }
+        }
+
+        let create =
+            |ext: &str| File::create(format!("{}.{}", out_prefix, ext)).map(BufWriter::new);
+        let mut smiss_writer = create("smiss")?;
+        let mut het_writer = create("het")?;
+        writeln!(smiss_writer, "#IID\tMISSING_CT\tOBS_CT\tF_MISS")?;
+        writeln!(
+            het_writer,
+            "#IID\tHET_CT\tHOM_ALT_CT\tO(HOM)\tE(HOM)\tOBS_CT\tF"
+        )?;
+        for ((_sam_idx, sam_rcd), sam_count) in sam_idx_rcds.iter().zip(sam_counts) {
+            let iid = &sam_rcd[sam_rcd_id_idx];
+            let f_miss = (num_kept_variants > 0)
+                .then(|| sam_count.missing as f64 / num_kept_variants as f64);
+            writeln!(
+                smiss_writer,
+                "{}\t{}\t{}\t{}",
+                iid,
+                sam_count.missing,
+                num_kept_variants,
+                fmt_stat(f_miss)
+            )?;
+            let called = num_kept_variants - sam_count.missing;
+            let observed_hom = called - sam_count.het;
+            let f = (called as f64 != sam_count.expected_hom).then(|| {
+                (observed_hom as f64 - sam_count.expected_hom)
+                    / (called as f64 - sam_count.expected_hom)
+            });
+            writeln!(
+                het_writer,
+                "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                iid,
+                sam_count.het,
+                sam_count.hom_alt,
+                observed_hom,
+                sam_count.expected_hom,
+                called,
+                fmt_stat(f)
+            )?;
+        }
+        for mut writer in [smiss_writer, het_writer] {
+            writer.flush()?;
+        }
+        Ok(())
+    }
+}
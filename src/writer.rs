@@ -0,0 +1,197 @@
+use crate::pfile::genotype_code;
+use csv::StringRecord;
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+
+/// The header of a storage mode 0x02 .pgen, with the variant and sample
+/// counts (at `PGEN_COUNTS_OFFSET`) left as zeros.
+const PGEN_HEADER: [u8; 12] = [0x6C, 0x1B, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0x40];
+
+/// Offset of the variant count in the .pgen header, followed by the sample
+/// count.
+const PGEN_COUNTS_OFFSET: u64 = 3;
+
+/// Writes a storage mode 0x02 .pgen (the one we read) a variant record at a
+/// time. The number of variants is only known, and written into the header,
+/// once `finish` is called.
+pub struct PgenWriter {
+    writer: BufWriter<File>,
+    num_samples: u32,
+    num_variants: u32,
+}
+
+impl PgenWriter {
+    pub fn create(path: String, num_samples: u32) -> io::Result<PgenWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        writer.write_all(&PGEN_HEADER)?;
+        Ok(PgenWriter {
+            writer,
+            num_samples,
+            num_variants: 0,
+        })
+    }
+
+    /// The size in bytes of each variant record.
+    pub fn record_size(&self) -> usize {
+        (self.num_samples as usize * 2).div_ceil(8)
+    }
+
+    /// Appends a packed variant record, of `record_size` bytes.
+    pub fn write_record(&mut self, record_buf: &[u8]) -> io::Result<()> {
+        debug_assert_eq!(record_buf.len(), self.record_size());
+        self.num_variants += 1;
+        self.writer.write_all(record_buf)
+    }
+
+    /// Writes the variant and sample counts into the header and flushes the
+    /// file.
+    pub fn finish(self) -> io::Result<()> {
+        let mut file = self.writer.into_inner().map_err(|err| err.into_error())?;
+        file.seek(SeekFrom::Start(PGEN_COUNTS_OFFSET))?;
+        file.write_all(&self.num_variants.to_le_bytes())?;
+        file.write_all(&self.num_samples.to_le_bytes())?;
+        file.flush()
+    }
+}
+
+/// Packs the genotypes of the samples at `sam_idxs` in a packed variant
+/// record into `out_buf`, in that order.
+pub fn pack_samples(record_buf: &[u8], sam_idxs: &[usize], out_buf: &mut Vec<u8>) {
+    out_buf.clear();
+    out_buf.resize((sam_idxs.len() * 2).div_ceil(8), 0);
+    for (out_idx, sam_idx) in sam_idxs.iter().enumerate() {
+        out_buf[out_idx / 4] |= genotype_code(record_buf, *sam_idx) << ((out_idx % 4) * 2);
+    }
+}
+
+/// Writes a .psam or .pvar a record at a time.
+pub struct MetadataWriter {
+    writer: BufWriter<File>,
+}
+
+impl MetadataWriter {
+    /// Creates the file and writes the `##` header lines (each ending in a
+    /// newline) and then the column names after a `#`.
+    pub fn create(
+        path: String,
+        header_lines: &str,
+        columns: &StringRecord,
+    ) -> io::Result<MetadataWriter> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        write!(writer, "{}", header_lines)?;
+        writeln!(
+            writer,
+            "#{}",
+            columns.iter().collect::<Vec<&str>>().join("\t")
+        )?;
+        Ok(MetadataWriter { writer })
+    }
+
+    pub fn write_record(&mut self, record: &StringRecord) -> io::Result<()> {
+        writeln!(
+            self.writer,
+            "{}",
+            record.iter().collect::<Vec<&str>>().join("\t")
+        )
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Writes the given samples of one or more pfiles (with the same samples) as
+/// a new pfile, a variant at a time.
+pub struct PfileWriter {
+    pgen_writer: PgenWriter,
+    pvar_writer: MetadataWriter,
+    /// The indices of the written samples in the input records.
+    sam_idxs: Vec<usize>,
+    /// Whether all samples are written, so that the records can be copied
+    /// as they are.
+    all_samples: bool,
+    record_buf: Vec<u8>,
+}
+
+impl PfileWriter {
+    /// Creates `{out_prefix}.pgen`, `{out_prefix}.pvar` (with the given `##`
+    /// header lines and columns) and `{out_prefix}.psam`, writing the given
+    /// sample records (out of `num_samples` in the input) to the latter.
+    pub fn create(
+        out_prefix: &str,
+        sam_header: &StringRecord,
+        sam_idx_rcds: &[(usize, StringRecord)],
+        num_samples: usize,
+        pvar_header_lines: &str,
+        pvar_columns: &StringRecord,
+    ) -> io::Result<PfileWriter> {
+        let mut psam_writer =
+            MetadataWriter::create(format!("{}.psam", out_prefix), "", sam_header)?;
+        for (_sam_idx, sam_rcd) in sam_idx_rcds.iter() {
+            psam_writer.write_record(sam_rcd)?;
+        }
+        psam_writer.finish()?;
+        let pvar_writer = MetadataWriter::create(
+            format!("{}.pvar", out_prefix),
+            pvar_header_lines,
+            pvar_columns,
+        )?;
+        let pgen_writer =
+            PgenWriter::create(format!("{}.pgen", out_prefix), sam_idx_rcds.len() as u32)?;
+        Ok(PfileWriter {
+            pgen_writer,
+            pvar_writer,
+            sam_idxs: sam_idx_rcds.iter().map(|(sam_idx, _)| *sam_idx).collect(),
+            all_samples: sam_idx_rcds.len() == num_samples,
+            record_buf: Vec::new(),
+        })
+    }
+
+    /// Appends a variant, given its .pvar record and its packed record in the
+    /// input .pgen.
+    pub fn write_variant(&mut self, var_rcd: &StringRecord, record_buf: &[u8]) -> io::Result<()> {
+        self.pvar_writer.write_record(var_rcd)?;
+        if self.all_samples {
+            self.pgen_writer.write_record(record_buf)
+        } else {
+            pack_samples(record_buf, &self.sam_idxs, &mut self.record_buf);
+            self.pgen_writer.write_record(&self.record_buf)
+        }
+    }
+
+    pub fn finish(self) -> io::Result<()> {
+        self.pvar_writer.finish()?;
+        self.pgen_writer.finish()
+    }
+}
+
+/// The name of a `##` header line, along with the ID of structured lines
+/// such as `##contig=<ID=1,length=248956422>`, which identify the lines
+/// meant to appear only once in a header.
+pub fn header_line_key(line: &str) -> (&str, Option<&str>) {
+    let line = line.trim_end().trim_start_matches("##");
+    let (name, value) = line.split_once('=').unwrap_or((line, ""));
+    let id = value.strip_prefix('<').and_then(|fields| {
+        fields
+            .trim_end_matches('>')
+            .split(',')
+            .find_map(|field| field.strip_prefix("ID="))
+    });
+    (name, id)
+}
+
+/// The union of the `##` header lines (each ending in a newline) of several
+/// files, in order of appearance, keeping only the first line with each
+/// `header_line_key`.
+pub fn union_header_lines<S: AsRef<str>>(headers: &[S]) -> String {
+    let mut keys = HashSet::new();
+    let mut union = String::new();
+    for line in headers.iter().flat_map(|header| header.as_ref().lines()) {
+        if keys.insert(header_line_key(line)) {
+            union.push_str(line);
+            union.push('\n');
+        }
+    }
+    union
+}
@@ -0,0 +1,97 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn bed_annotations() {
+    let dir = TempDir::new("annotate-bed");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    // 0-based half-open intervals, so GENE1 only holds position 100 and GENE3
+    // starts after position 50; overlapping ones are joined in order of start
+    let bed = dir.path("genes.bed");
+    std::fs::write(
+        &bed,
+        "chr1\t99\t100\tGENE1\nchr1\t90\t250\tGENE2\n2\t50\t60\tGENE3\n",
+    )
+    .unwrap();
+    let out = dir.path("out");
+    pgen_rs(&["--annotations", &bed, "annotate", &pfile, "-o", &out]);
+    assert_eq!(
+        read(&format!("{}.pvar", out)),
+        "#CHROM\tPOS\tID\tREF\tALT\tNAME\n\
+         1\t100\trs1\tA\tG\tGENE2,GENE1\n\
+         1\t200\trs2\tC\tT\tGENE2\n\
+         2\t50\trs3\tG\tA\t.\n"
+    );
+    assert_eq!(read_genotypes(&out), GENOTYPES);
+    assert_eq!(read(&format!("{}.psam", out)), PSAM);
+
+    pgen_rs(&[
+        "--annotations",
+        &bed,
+        "annotate",
+        &pfile,
+        "--vcf",
+        "--include-var",
+        "NAME != \".\"",
+        "--include-sam",
+        "SEX == \"1\"",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(
+        read(&format!("{}.vcf", out)),
+        format!(
+            "##fileformat=VCFv4.2\n\
+             ##source=pgen-rs\n\
+             ##INFO=<ID=NAME,Number=.,Type=String,Description=\"NAME from {}\">\n\
+             #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tA\tC\n\
+             1\t100\trs1\tA\tG\t.\t.\tNAME=GENE2,GENE1\tGT\t0/0\t1/1\n\
+             1\t200\trs2\tC\tT\t.\t.\tNAME=GENE2\tGT\t1/1\t0/1\n",
+            bed
+        )
+    );
+}
+
+#[test]
+fn tsv_and_vcf_annotations() {
+    let dir = TempDir::new("annotate-tsv");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    // matched by ID, lacking CHROM, POS, REF and ALT columns
+    let tsv = dir.path("csq.tsv");
+    std::fs::write(&tsv, "ID\tCSQ\nrs2\tmissense\nrs3\tsynonymous\n").unwrap();
+    let stdout = pgen_rs(&[
+        "--annotations",
+        &tsv,
+        "query",
+        &pfile,
+        "-i",
+        "CSQ != \"synonymous\"",
+        "-f",
+        "ID + \" \" + CSQ",
+    ]);
+    assert_eq!(stdout, "rs1 .\nrs2 missense\n");
+
+    // matched by CHROM, POS, REF and any of the ALT alleles
+    let vcf = dir.path("af.vcf");
+    std::fs::write(
+        &vcf,
+        "##fileformat=VCFv4.2\n\
+         #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\n\
+         chr1\t100\t.\tA\tT,G\t.\t.\tAF=0.1\n\
+         1\t200\t.\tC\tA\t.\t.\tAF=0.3\n\
+         2\t50\t.\tG\tA\t.\t.\tAF=0.2\n",
+    )
+    .unwrap();
+    let stdout = pgen_rs(&[
+        "--annotations",
+        &vcf,
+        "query",
+        &pfile,
+        "-f",
+        "ID + \" \" + AF",
+    ]);
+    assert_eq!(stdout, "rs1 0.1\nrs2 .\nrs3 0.2\n");
+}
@@ -0,0 +1,119 @@
+mod common;
+
+use common::*;
+
+// S7 misses both phenotypes, so it is left out
+const PSAM: &str = "#IID\tQT\tCC\tAGE\n\
+                    S1\t1.0\t1\t30\n\
+                    S2\t2.5\t1\t41\n\
+                    S3\t4.0\t2\t35\n\
+                    S4\t0.5\t2\t50\n\
+                    S5\t3.0\t1\t28\n\
+                    S6\t4.5\t2\t33\n\
+                    S7\tNA\t0\t60\n";
+const PVAR: &str = "#CHROM\tPOS\tID\tREF\tALT\n\
+                    1\t100\tv1\tA\tC\n\
+                    1\t200\tv2\tA\tG\n";
+// v2 is monomorphic among the samples with a phenotype
+const GENOTYPES: [&str; 2] = ["0120122", "0000002"];
+
+#[test]
+fn linear_regression() {
+    let dir = TempDir::new("assoc-linear");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&["assoc", &pfile, "--pheno-name", "QT", "-o", &out]);
+    let glm = read_tsv(&format!("{}.QT.glm.linear", out));
+    assert_eq!(
+        glm[0].join("\t"),
+        "#CHROM\tPOS\tID\tREF\tALT\tA1\tTEST\tOBS_CT\tBETA\tSE\tT_STAT\tP"
+    );
+    assert_eq!(glm[1][..8], ["1", "100", "v1", "A", "C", "C", "ADD", "6"]);
+    // Sxx = 4, Sxy = 7 and the residual sum of squares is 11/24, over 4
+    // degrees of freedom
+    let (beta, se) = (7.0 / 4.0, (11.0f64 / 24.0 / 4.0 / 4.0).sqrt());
+    let t: f64 = beta / se;
+    // the two-sided p-value of Student's t distribution with 4 degrees of
+    // freedom, whose CDF is 1/2 + 3/4 · u (1 - u² / 3) for u = t / sqrt(t² + 4)
+    let u = t / (t * t + 4.0).sqrt();
+    let p = 1.0 - 3.0 / 2.0 * u * (1.0 - u * u / 3.0);
+    for (value, expected) in glm[1][8..].iter().zip([beta, se, t, p]) {
+        assert_close(value.parse().unwrap(), expected, 1e-12);
+    }
+    assert_eq!(glm[2][7..], ["6", "NA", "NA", "NA", "NA"]);
+
+    // adjusting for AGE, by the exact least squares solution
+    pgen_rs(&[
+        "assoc",
+        &pfile,
+        "--pheno-name",
+        "QT",
+        "--covar",
+        "AGE",
+        "-o",
+        &out,
+    ]);
+    let glm = read_tsv(&format!("{}.QT.glm.linear", out));
+    assert_close(glm[1][8].parse().unwrap(), 11693.0 / 7076.0, 1e-12);
+}
+
+#[test]
+fn logistic_regression() {
+    let dir = TempDir::new("assoc-logistic");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&["assoc", &pfile, "--pheno-name", "CC", "-o", &out]);
+    let glm = read_tsv(&format!("{}.CC.glm.logistic", out));
+    assert_eq!(
+        glm[0].join("\t"),
+        "#CHROM\tPOS\tID\tREF\tALT\tA1\tTEST\tOBS_CT\tBETA\tSE\tZ_STAT\tP"
+    );
+    assert_eq!(glm[1][..8], ["1", "100", "v1", "A", "C", "C", "ADD", "6"]);
+    // the cases are 1 of 2 samples with no ALT allele, 0 of 2 with one and
+    // 2 of 2 with two, for which the likelihood is maximised by a log odds
+    // ratio of ln 3 with a standard error of 2 / sqrt(3) (as found by
+    // Newton's method), and a two-sided normal p-value of 0.3413880904342417
+    let (beta, se) = (3.0f64.ln(), 2.0 / 3.0f64.sqrt());
+    let expected = [beta, se, beta / se, 0.3413880904342417];
+    for (value, expected) in glm[1][8..].iter().zip(expected) {
+        assert_close(value.parse().unwrap(), expected, 1e-9);
+    }
+    assert_eq!(glm[2][7..], ["6", "NA", "NA", "NA", "NA"]);
+
+    // without any control among the kept samples, CC is taken as a
+    // quantitative phenotype
+    pgen_rs(&[
+        "assoc",
+        &pfile,
+        "--pheno-name",
+        "CC",
+        "--include-sam",
+        "CC == \"2\"",
+        "-o",
+        &out,
+    ]);
+    let glm = read_tsv(&format!("{}.CC.glm.linear", out));
+    assert_eq!(glm[1][7], "3");
+}
+
+#[test]
+fn missing_phenotype() {
+    let dir = TempDir::new("assoc-missing");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let stderr = pgen_rs_fails(&[
+        "assoc",
+        &pfile,
+        "--pheno-name",
+        "QT",
+        "--include-sam",
+        "IID == \"S7\"",
+    ]);
+    assert!(
+        stderr.contains("no kept sample has a QT value"),
+        "{}",
+        stderr
+    );
+}
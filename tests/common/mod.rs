@@ -0,0 +1,180 @@
+//! Helpers shared by the integration tests, which run pgen-rs over small
+//! pfiles written by hand and check its outputs.
+#![allow(dead_code)]
+
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The .psam of the pfile most tests run over, with five samples (one of
+/// them of unknown sex).
+pub const PSAM: &str = "#IID\tSEX\nA\t1\nB\t2\nC\t1\nD\t2\nE\tNA\n";
+
+/// The .pvar of the pfile most tests run over, with three variants.
+pub const PVAR: &str = "#CHROM\tPOS\tID\tREF\tALT\n\
+                        1\t100\trs1\tA\tG\n\
+                        1\t200\trs2\tC\tT\n\
+                        2\t50\trs3\tG\tA\n";
+
+/// The genotypes of the pfile most tests run over, in the notation of
+/// `write_pfile`: rs2 is missing for B and rs3 is monomorphic.
+pub const GENOTYPES: [&str; 3] = ["01201", "2.110", "00000"];
+
+/// A directory of its own under the system temp directory, removed when
+/// dropped.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!(
+            "pgen-rs-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+
+    /// The path of `file_name` in the directory.
+    pub fn path(&self, file_name: &str) -> String {
+        self.0.join(file_name).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Writes the pfile `{prefix}.pgen`, `.psam` and `.pvar`, with the genotypes
+/// of each variant given as a string of one character per sample: `0`, `1`
+/// or `2` for the ALT allele count, or `.` for a missing call.
+pub fn write_pfile(prefix: &str, psam: &str, pvar: &str, genotypes: &[&str]) {
+    let num_samples = genotypes.first().map_or(0, |variant| variant.len());
+    fs::write(format!("{}.psam", prefix), psam).unwrap();
+    fs::write(format!("{}.pvar", prefix), pvar).unwrap();
+    fs::write(
+        format!("{}.pgen", prefix),
+        pack_pgen(genotypes, num_samples),
+    )
+    .unwrap();
+}
+
+/// The bytes of a .pgen (in the fixed-width hardcall mode) holding the given
+/// genotypes, in the format of `write_pfile`.
+pub fn pack_pgen(genotypes: &[&str], num_samples: usize) -> Vec<u8> {
+    let mut pgen = vec![0x6c, 0x1b, 0x02];
+    pgen.extend((genotypes.len() as u32).to_le_bytes());
+    pgen.extend((num_samples as u32).to_le_bytes());
+    pgen.push(0x40);
+    for variant in genotypes {
+        assert_eq!(variant.len(), num_samples);
+        let mut record = vec![0u8; (num_samples * 2).div_ceil(8)];
+        for (sam_idx, genotype) in variant.chars().enumerate() {
+            let code = match genotype {
+                '0' => 0b00,
+                '1' => 0b01,
+                '2' => 0b10,
+                '.' => 0b11,
+                _ => panic!("unexpected genotype {}", genotype),
+            };
+            record[sam_idx / 4] |= code << ((sam_idx % 4) * 2);
+        }
+        pgen.extend(record);
+    }
+    pgen
+}
+
+/// Reads back the genotypes of `{prefix}.pgen`, in the format of
+/// `write_pfile`.
+pub fn read_genotypes(prefix: &str) -> Vec<String> {
+    let pgen = fs::read(format!("{}.pgen", prefix)).unwrap();
+    assert_eq!(pgen[..3], [0x6c, 0x1b, 0x02]);
+    let num_variants = u32::from_le_bytes(pgen[3..7].try_into().unwrap()) as usize;
+    let num_samples = u32::from_le_bytes(pgen[7..11].try_into().unwrap()) as usize;
+    let record_size = (num_samples * 2).div_ceil(8);
+    assert_eq!(pgen.len(), 12 + num_variants * record_size);
+    (0..num_variants)
+        .map(|var_idx| {
+            let record = &pgen[12 + var_idx * record_size..][..record_size];
+            (0..num_samples)
+                .map(
+                    |sam_idx| match (record[sam_idx / 4] >> ((sam_idx % 4) * 2)) & 0b11 {
+                        0b00 => '0',
+                        0b01 => '1',
+                        0b10 => '2',
+                        _ => '.',
+                    },
+                )
+                .collect()
+        })
+        .collect()
+}
+
+fn run(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pgen-rs"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Runs pgen-rs with `args`, returning its stdout, and panics with its
+/// stderr if it fails.
+pub fn pgen_rs(args: &[&str]) -> String {
+    let output = run(args);
+    assert!(
+        output.status.success(),
+        "pgen-rs {} failed:\n{}",
+        args.join(" "),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    String::from_utf8(output.stdout).unwrap()
+}
+
+/// Runs pgen-rs with `args`, expecting it to fail, and returns its stderr.
+pub fn pgen_rs_fails(args: &[&str]) -> String {
+    let output = run(args);
+    assert!(
+        !output.status.success(),
+        "pgen-rs {} succeeded",
+        args.join(" ")
+    );
+    String::from_utf8(output.stderr).unwrap()
+}
+
+/// Reads a file.
+pub fn read(path: &str) -> String {
+    fs::read_to_string(path).unwrap()
+}
+
+/// Reads a tab-separated file into the fields of each line.
+pub fn read_tsv(path: &str) -> Vec<Vec<String>> {
+    read(path)
+        .lines()
+        .map(|line| line.split('\t').map(str::to_string).collect())
+        .collect()
+}
+
+/// The lines of a file not starting with `#`.
+pub fn body_lines(path: &str) -> Vec<String> {
+    read(path)
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Asserts that two floats are within `tol` of each other.
+pub fn assert_close(actual: f64, expected: f64, tol: f64) {
+    assert!(
+        (actual - expected).abs() <= tol,
+        "{} is not within {} of {}",
+        actual,
+        tol,
+        expected
+    );
+}
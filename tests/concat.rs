@@ -0,0 +1,117 @@
+mod common;
+
+use common::*;
+
+const PSAM: &str = "#IID\tSEX\nA\t1\nB\t2\nC\t1\nD\t2\nE\tNA\n";
+
+/// Writes a pfile per chromosome with the samples above, the second one with
+/// an extra column and header lines of its own.
+fn write_pfiles(dir: &TempDir) -> (String, String) {
+    let (pfile1, pfile2) = (dir.path("chr1"), dir.path("chr2"));
+    write_pfile(
+        &pfile1,
+        PSAM,
+        "##fileformat=VCFv4.3\n\
+         ##contig=<ID=1,length=1000>\n\
+         #CHROM\tPOS\tID\tREF\tALT\n\
+         1\t100\trs1\tA\tG\n\
+         1\t200\trs2\tC\tT\n",
+        &["01201", "2.110"],
+    );
+    write_pfile(
+        &pfile2,
+        PSAM,
+        "##fileformat=VCFv4.3\n\
+         ##contig=<ID=2,length=500>\n\
+         ##INFO=<ID=AF,Number=A,Type=Float,Description=\"Frequency\">\n\
+         #CHROM\tPOS\tID\tREF\tALT\tINFO\n\
+         2\t50\trs3\tG\tA\tAF=0.1\n",
+        &["0000."],
+    );
+    (pfile1, pfile2)
+}
+
+#[test]
+fn concat_pfiles() {
+    let dir = TempDir::new("concat");
+    let (pfile1, pfile2) = write_pfiles(&dir);
+    let out = dir.path("out");
+    pgen_rs(&["concat", &pfile1, &pfile2, "-o", &out]);
+    assert_eq!(read_genotypes(&out), ["01201", "2.110", "0000."]);
+    assert_eq!(read(&format!("{}.psam", out)), PSAM);
+    assert_eq!(
+        read(&format!("{}.pvar", out)),
+        "##fileformat=VCFv4.3\n\
+         ##contig=<ID=1,length=1000>\n\
+         ##contig=<ID=2,length=500>\n\
+         ##INFO=<ID=AF,Number=A,Type=Float,Description=\"Frequency\">\n\
+         #CHROM\tPOS\tID\tREF\tALT\tINFO\n\
+         1\t100\trs1\tA\tG\t.\n\
+         1\t200\trs2\tC\tT\t.\n\
+         2\t50\trs3\tG\tA\tAF=0.1\n"
+    );
+
+    // the kept samples are repacked
+    pgen_rs(&[
+        "concat",
+        &pfile1,
+        &pfile2,
+        "--include-sam",
+        "IID != \"A\" && IID != \"C\"",
+        "--include-var",
+        "ID != \"rs2\"",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(read_genotypes(&out), ["101", "00."]);
+    assert_eq!(
+        read(&format!("{}.psam", out)),
+        "#IID\tSEX\nB\t2\nD\t2\nE\tNA\n"
+    );
+
+    let vcf = format!("{}.vcf", out);
+    pgen_rs(&["concat", &pfile2, &pfile1, "--vcf", "-o", &out]);
+    assert_eq!(
+        body_lines(&vcf),
+        [
+            "2\t50\trs3\tG\tA\t.\t.\tAF=0.1\tGT\t0/0\t0/0\t0/0\t0/0\t./.",
+            "1\t100\trs1\tA\tG\t.\t.\t.\tGT\t0/0\t0/1\t1/1\t0/0\t0/1",
+            "1\t200\trs2\tC\tT\t.\t.\t.\tGT\t1/1\t./.\t0/1\t0/1\t0/0",
+        ]
+    );
+}
+
+#[test]
+fn concat_needs_the_same_samples() {
+    let dir = TempDir::new("concat-samples");
+    let (pfile1, pfile2) = write_pfiles(&dir);
+    std::fs::write(
+        format!("{}.psam", pfile2),
+        "#IID\tSEX\nA\t1\nC\t1\nB\t2\nD\t2\nE\tNA\n",
+    )
+    .unwrap();
+    pgen_rs_fails(&["concat", &pfile1, &pfile2, "-o", &dir.path("out")]);
+}
+
+#[test]
+fn concat_compares_each_id_column() {
+    let dir = TempDir::new("concat-id-columns");
+    let (pfile1, pfile2) = write_pfiles(&dir);
+    // the IDs only match once pasted together
+    std::fs::write(
+        format!("{}.psam", pfile1),
+        "#FID\tIID\nx_y\tz\nF\tB\nF\tC\nF\tD\nF\tE\n",
+    )
+    .unwrap();
+    std::fs::write(
+        format!("{}.psam", pfile2),
+        "#FID\tIID\nx\ty_z\nF\tB\nF\tC\nF\tD\nF\tE\n",
+    )
+    .unwrap();
+    let stderr = pgen_rs_fails(&["concat", &pfile1, &pfile2, "-o", &dir.path("out")]);
+    assert!(
+        stderr.contains("FID x, IID y_z and SID 0 instead of x_y, z and 0"),
+        "{}",
+        stderr
+    );
+}
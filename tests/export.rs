@@ -0,0 +1,181 @@
+mod common;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::{Int64Type, Int8Type};
+use common::*;
+
+#[test]
+fn npy_matrix() {
+    let dir = TempDir::new("export-npy");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&[
+        "export",
+        &pfile,
+        "--format",
+        "npy",
+        "--include-sam",
+        "IID != \"C\"",
+        "--include-var",
+        "ID != \"rs3\"",
+        "-o",
+        &out,
+    ]);
+
+    let npy = std::fs::read(format!("{}.npy", out)).unwrap();
+    assert_eq!(npy[..8], *b"\x93NUMPY\x01\x00");
+    let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+    assert_eq!((10 + header_len) % 64, 0);
+    let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+    assert!(header.starts_with("{'descr': '|i1', 'fortran_order': False, 'shape': (2, 4), }"));
+    assert!(header.ends_with('\n'));
+    let matrix = npy[10 + header_len..]
+        .iter()
+        .map(|byte| *byte as i8)
+        .collect::<Vec<i8>>();
+    assert_eq!(matrix, [0, 1, 0, 1, 2, -1, 1, 0]);
+
+    assert_eq!(
+        read(&format!("{}.variants.tsv", out)),
+        "CHROM\tPOS\tID\tREF\tALT\n1\t100\trs1\tA\tG\n1\t200\trs2\tC\tT\n"
+    );
+    assert_eq!(
+        read(&format!("{}.samples.tsv", out)),
+        "IID\tSEX\nA\t1\nB\t2\nD\t2\nE\tNA\n"
+    );
+}
+
+/// Reads a Parquet or Arrow IPC file, holding a single record batch.
+fn read_table(path: &str) -> arrow_array::RecordBatch {
+    let file = std::fs::File::open(path).unwrap();
+    let batches = if path.ends_with(".parquet") {
+        parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder::try_new(file)
+            .unwrap()
+            .build()
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    } else {
+        arrow_ipc::reader::FileReader::try_new(file, None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    };
+    assert_eq!(batches.len(), 1);
+    batches.into_iter().next().unwrap()
+}
+
+#[test]
+fn typed_tables() {
+    let dir = TempDir::new("export-tables");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    for format in ["parquet", "arrow"] {
+        let out = dir.path(format);
+        pgen_rs(&[
+            "export",
+            &pfile,
+            "--format",
+            format,
+            "--long",
+            "--include-sam",
+            "IID == \"B\" || IID == \"E\"",
+            "--include-var",
+            "CHROM == \"1\"",
+            "-o",
+            &out,
+        ]);
+
+        let variants = read_table(&format!("{}.variants.{}", out, format));
+        assert_eq!(variants.num_rows(), 2);
+        let chrom = variants.column_by_name("CHROM").unwrap().as_string::<i32>();
+        assert_eq!(chrom.iter().collect::<Vec<_>>(), [Some("1"), Some("1")]);
+        let pos = variants
+            .column_by_name("POS")
+            .unwrap()
+            .as_primitive::<Int64Type>();
+        assert_eq!(pos.iter().collect::<Vec<_>>(), [Some(100), Some(200)]);
+
+        let samples = read_table(&format!("{}.samples.{}", out, format));
+        let sex = samples
+            .column_by_name("SEX")
+            .unwrap()
+            .as_primitive::<Int64Type>();
+        assert_eq!(sex.iter().collect::<Vec<_>>(), [Some(2), None]);
+
+        let genotypes = read_table(&format!("{}.genotypes.{}", out, format));
+        let ids = genotypes.column_by_name("ID").unwrap().as_string::<i32>();
+        let iids = genotypes.column_by_name("IID").unwrap().as_string::<i32>();
+        let gts = genotypes
+            .column_by_name("GT")
+            .unwrap()
+            .as_primitive::<Int8Type>();
+        assert_eq!(
+            ids.iter()
+                .zip(iids.iter())
+                .zip(gts.iter())
+                .collect::<Vec<_>>(),
+            [
+                ((Some("rs1"), Some("B")), Some(1)),
+                ((Some("rs1"), Some("E")), Some(1)),
+                ((Some("rs2"), Some("B")), None),
+                ((Some("rs2"), Some("E")), Some(0)),
+            ]
+        );
+    }
+
+    // the long table only comes with the typed formats, and the sample-major
+    // layout with the matrices
+    let stderr = pgen_rs_fails(&["export", &pfile, "--format", "npy", "--long"]);
+    assert!(stderr.contains("--long only applies"), "{}", stderr);
+    let stderr = pgen_rs_fails(&["export", &pfile, "--format", "arrow", "--sample-major"]);
+    assert!(stderr.contains("--sample-major only applies"), "{}", stderr);
+}
+
+#[test]
+fn sample_major_matrices() {
+    let dir = TempDir::new("export-sample-major");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&[
+        "export",
+        &pfile,
+        "--format",
+        "tsv",
+        "--sample-major",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(
+        read(&format!("{}.tsv", out)),
+        "IID\trs1\trs2\trs3\n\
+         A\t0\t2\t0\n\
+         B\t1\tNA\t0\n\
+         C\t2\t1\t0\n\
+         D\t0\t1\t0\n\
+         E\t1\t0\t0\n"
+    );
+
+    pgen_rs(&[
+        "export",
+        &pfile,
+        "--format",
+        "npy",
+        "--sample-major",
+        "--include-sam",
+        "IID != \"A\"",
+        "-o",
+        &out,
+    ]);
+    let npy = std::fs::read(format!("{}.npy", out)).unwrap();
+    let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+    let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+    assert!(header.contains("'shape': (4, 3)"));
+    let matrix = npy[10 + header_len..]
+        .iter()
+        .map(|byte| *byte as i8)
+        .collect::<Vec<i8>>();
+    assert_eq!(matrix, [1, -1, 0, 2, 1, 0, 0, 1, 0, 1, 0, 0]);
+}
@@ -0,0 +1,293 @@
+mod common;
+
+use common::*;
+
+const VCF: &str = "##fileformat=VCFv4.2\n\
+                   ##source=pgen-rs\n\
+                   #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tA\tB\tC\tD\tE\n\
+                   1\t100\trs1\tA\tG\t.\t.\t.\tGT\t0/0\t0/1\t1/1\t0/0\t0/1\n\
+                   1\t200\trs2\tC\tT\t.\t.\t.\tGT\t1/1\t./.\t0/1\t0/1\t0/0\n\
+                   2\t50\trs3\tG\tA\t.\t.\t.\tGT\t0/0\t0/0\t0/0\t0/0\t0/0\n";
+
+#[test]
+fn mmap_reads() {
+    let dir = TempDir::new("filter-mmap");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let vcf = dir.path("f.vcf");
+    pgen_rs(&["filter", &pfile, "-o", &vcf]);
+    assert_eq!(read(&vcf), VCF);
+    pgen_rs(&["--mmap", "filter", &pfile, "-o", &vcf]);
+    assert_eq!(read(&vcf), VCF);
+}
+
+/// The VCF genotype of each character of the genotypes of `write_pfile`.
+fn vcf_genotype(genotype: char) -> &'static str {
+    match genotype {
+        '0' => "0/0",
+        '1' => "0/1",
+        '2' => "1/1",
+        _ => "./.",
+    }
+}
+
+#[test]
+fn threaded_rendering() {
+    // enough variants for several chunks of lines per thread
+    let genotypes = (0..4000)
+        .map(|var_idx| {
+            (0..6)
+                .map(|sam_idx| ['0', '1', '2', '.'][(var_idx * 7 + sam_idx * 3) % 4])
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>();
+    // the kept variants are two in every three, and the kept samples all but S2
+    let pvar = (0..4000).fold(
+        String::from("#CHROM\tPOS\tID\tREF\tALT\tKEEP\n"),
+        |pvar, var_idx| {
+            pvar + &format!(
+                "1\t{}\tv{}\tA\tC\t{}\n",
+                var_idx + 1,
+                var_idx,
+                u8::from(var_idx % 3 != 0)
+            )
+        },
+    );
+    let dir = TempDir::new("filter-threads");
+    let pfile = dir.path("f");
+    write_pfile(
+        &pfile,
+        "#IID\nS1\nS2\nS3\nS4\nS5\nS6\n",
+        &pvar,
+        &genotypes.iter().map(String::as_str).collect::<Vec<&str>>(),
+    );
+
+    let expected = (0..4000)
+        .filter(|var_idx| var_idx % 3 != 0)
+        .map(|var_idx| {
+            let sam_genotypes = genotypes[var_idx]
+                .chars()
+                .enumerate()
+                .filter(|(sam_idx, _genotype)| *sam_idx != 1)
+                .map(|(_sam_idx, genotype)| vcf_genotype(genotype))
+                .collect::<Vec<&str>>();
+            format!(
+                "1\t{}\tv{}\tA\tC\t.\t.\t.\tGT\t{}",
+                var_idx + 1,
+                var_idx,
+                sam_genotypes.join("\t")
+            )
+        })
+        .collect::<Vec<String>>();
+    for threads in ["1", "3"] {
+        let vcf = dir.path(&format!("f{}.vcf", threads));
+        pgen_rs(&[
+            "filter",
+            &pfile,
+            "--threads",
+            threads,
+            "--include-sam",
+            "IID != \"S2\"",
+            "--include-var",
+            "KEEP == \"1\"",
+            "-o",
+            &vcf,
+        ]);
+        assert_eq!(body_lines(&vcf), expected);
+    }
+}
+
+#[test]
+fn genotype_codes_at_every_position() {
+    // each code at each of the four positions of a byte, over a partial byte
+    let genotypes = (0..4)
+        .map(|var_idx| {
+            (0..7)
+                .map(|sam_idx| ['0', '1', '2', '.'][(var_idx + sam_idx) % 4])
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>();
+    let dir = TempDir::new("filter-codes");
+    let pfile = dir.path("f");
+    write_pfile(
+        &pfile,
+        "#IID\nS1\nS2\nS3\nS4\nS5\nS6\nS7\n",
+        "#CHROM\tPOS\tID\tREF\tALT\n1\t1\tv0\tA\tC\n1\t2\tv1\tA\tC\n1\t3\tv2\tA\tC\n1\t4\tv3\tA\tC\n",
+        &genotypes.iter().map(String::as_str).collect::<Vec<&str>>(),
+    );
+    let vcf = dir.path("f.vcf");
+    pgen_rs(&["filter", &pfile, "-o", &vcf]);
+    assert_eq!(
+        body_lines(&vcf),
+        [
+            "1\t1\tv0\tA\tC\t.\t.\t.\tGT\t0/0\t0/1\t1/1\t./.\t0/0\t0/1\t1/1",
+            "1\t2\tv1\tA\tC\t.\t.\t.\tGT\t0/1\t1/1\t./.\t0/0\t0/1\t1/1\t./.",
+            "1\t3\tv2\tA\tC\t.\t.\t.\tGT\t1/1\t./.\t0/0\t0/1\t1/1\t./.\t0/0",
+            "1\t4\tv3\tA\tC\t.\t.\t.\tGT\t./.\t0/0\t0/1\t1/1\t./.\t0/0\t0/1",
+        ]
+    );
+}
+
+#[test]
+fn include_expressions() {
+    let dir = TempDir::new("filter-include");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let vcf = dir.path("f.vcf");
+    pgen_rs(&[
+        "filter",
+        &pfile,
+        "--include-var",
+        "CHROM == \"1\" && REF != \"A\"",
+        "--include-sam",
+        "SEX == \"2\" || IID == \"E\"",
+        "-o",
+        &vcf,
+    ]);
+    assert_eq!(
+        read(&vcf),
+        "##fileformat=VCFv4.2\n\
+         ##source=pgen-rs\n\
+         #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tB\tD\tE\n\
+         1\t200\trs2\tC\tT\t.\t.\t.\tGT\t./.\t0/1\t0/0\n"
+    );
+
+    pgen_rs(&[
+        "filter",
+        &pfile,
+        "--include-var",
+        "CHROM == \"3\"",
+        "-o",
+        &vcf,
+    ]);
+    assert_eq!(body_lines(&vcf), Vec::<String>::new());
+}
+
+#[test]
+fn zstd_metadata() {
+    let dir = TempDir::new("filter-zstd");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    for ext in ["psam", "pvar"] {
+        let path = format!("{}.{}", pfile, ext);
+        let compressed = zstd::encode_all(read(&path).as_bytes(), 0).unwrap();
+        std::fs::write(format!("{}.zst", path), compressed).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+    let vcf = dir.path("f.vcf");
+    pgen_rs(&["filter", &pfile, "-o", &vcf]);
+    assert_eq!(read(&vcf), VCF);
+}
+
+#[test]
+fn headerless_metadata() {
+    let dir = TempDir::new("filter-headerless");
+    let pfile = dir.path("f");
+    // .bim and .fam column orders, with and without the CM column
+    for pvar in [
+        "1\trs1\t0\t100\tG\tA\n1\trs2\t0\t200\tT\tC\n2\trs3\t0\t50\tA\tG\n",
+        "1\trs1\t100\tG\tA\n1\trs2\t200\tT\tC\n2\trs3\t50\tA\tG\n",
+    ] {
+        write_pfile(
+            &pfile,
+            "F1\tA\t0\t0\t1\nF1\tB\t0\t0\t2\nF2\tC\t0\t0\t1\nF2\tD\t0\t0\t2\nF3\tE\t0\t0\t0\n",
+            pvar,
+            &GENOTYPES,
+        );
+        let vcf = dir.path("f.vcf");
+        pgen_rs(&[
+            "filter",
+            &pfile,
+            "--include-var",
+            "POS == \"200\"",
+            "--include-sam",
+            "FID == \"F2\" || SEX == \"1\"",
+            "-o",
+            &vcf,
+        ]);
+        assert_eq!(
+            read(&vcf).lines().skip(2).collect::<Vec<&str>>(),
+            [
+                "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tA\tC\tD",
+                "1\t200\trs2\tC\tT\t.\t.\t.\tGT\t1/1\t0/1\t0/1",
+            ]
+        );
+    }
+}
+
+#[test]
+fn composite_sample_ids() {
+    let dir = TempDir::new("filter-ids");
+    let pfile = dir.path("f");
+    // two families with a sample A each
+    write_pfile(
+        &pfile,
+        "#FID\tIID\tSEX\nF1\tA\t1\nF1\tB\t2\nF2\tA\t1\nF2\tD\t2\nF3\tE\tNA\n",
+        PVAR,
+        &GENOTYPES,
+    );
+    let vcf = dir.path("f.vcf");
+    pgen_rs(&[
+        "filter",
+        &pfile,
+        "--include-sam",
+        "FID == \"F2\" && IID == \"A\"",
+        "--include-var",
+        "ID == \"rs1\"",
+        "-o",
+        &vcf,
+    ]);
+    // the samples are named by their IID unless asked otherwise
+    assert!(read(&vcf).contains("FORMAT\tA\n"));
+    pgen_rs(&[
+        "filter",
+        &pfile,
+        "--include-sam",
+        "FID == \"F2\" && IID == \"A\"",
+        "--include-var",
+        "ID == \"rs1\"",
+        "--id-paste",
+        "maybe-fid,iid,maybe-sid",
+        "-o",
+        &vcf,
+    ]);
+    assert_eq!(
+        read(&vcf).lines().skip(2).collect::<Vec<&str>>(),
+        [
+            "#CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tF2_A",
+            "1\t100\trs1\tA\tG\t.\t.\t.\tGT\t1/1",
+        ]
+    );
+
+    pgen_rs(&[
+        "filter",
+        &pfile,
+        "--id-paste",
+        "iid,fid",
+        "--id-delim",
+        ":",
+        "-o",
+        &vcf,
+    ]);
+    assert!(read(&vcf).contains("FORMAT\tA:F1\tB:F1\tA:F2\tD:F2\tE:F3\n"));
+
+    // maybe-fid leaves out a FID column of only 0s
+    write_pfile(
+        &pfile,
+        "#FID\tIID\tSEX\n0\tA\t1\n0\tB\t2\n0\tC\t1\n0\tD\t2\n0\tE\tNA\n",
+        PVAR,
+        &GENOTYPES,
+    );
+    pgen_rs(&["filter", &pfile, "--id-paste", "maybe-fid,iid", "-o", &vcf]);
+    assert_eq!(read(&vcf), VCF);
+
+    // and there is no naming the samples without an IID column
+    write_pfile(
+        &pfile,
+        "#FID\tSEX\nA\t1\nB\t2\nC\t1\nD\t2\nE\tNA\n",
+        PVAR,
+        &GENOTYPES,
+    );
+    let stderr = pgen_rs_fails(&["filter", &pfile, "-o", &vcf]);
+    assert!(stderr.contains("IID not among the headers"), "{}", stderr);
+}
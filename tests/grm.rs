@@ -0,0 +1,119 @@
+mod common;
+
+use common::*;
+
+/// The lower triangle of the GRM of the samples of `PSAM`: the mean over rs1
+/// (ALT frequency 0.4) and rs2 (0.5, without B) of the products of the
+/// standardized ALT allele counts (x - 2p) / sqrt(2p(1 - p)), rs3 being
+/// monomorphic. The diagonal is the mean of GCTA's estimator
+/// 1 + (x² - (1 + 2p)x + 2p²) / (2p(1 - p)).
+const GRM: [f64; 15] = [
+    11.0 / 6.0,
+    -1.0 / 3.0,
+    0.0,
+    -1.0,
+    1.0 / 2.0,
+    5.0 / 4.0,
+    2.0 / 3.0,
+    -1.0 / 3.0,
+    -1.0,
+    5.0 / 6.0,
+    -7.0 / 6.0,
+    1.0 / 12.0,
+    1.0 / 4.0,
+    -1.0 / 6.0,
+    1.0,
+];
+
+/// The number of variants called for both samples of each entry of `GRM`.
+const GRM_N: [f32; 15] = [
+    2.0, 1.0, 1.0, 2.0, 1.0, 2.0, 2.0, 1.0, 2.0, 2.0, 2.0, 1.0, 2.0, 2.0, 2.0,
+];
+
+fn read_f32s(path: &str) -> Vec<f32> {
+    std::fs::read(path)
+        .unwrap()
+        .chunks(4)
+        .map(|bytes| f32::from_le_bytes(bytes.try_into().unwrap()))
+        .collect()
+}
+
+#[test]
+fn gcta_grm() {
+    let dir = TempDir::new("grm-gcta");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&["grm", &pfile, "-o", &out]);
+    let grm = read_f32s(&format!("{}.grm.bin", out));
+    assert_eq!(grm.len(), GRM.len());
+    for (actual, expected) in std::iter::zip(grm, GRM) {
+        assert_close(actual as f64, expected, 1e-6);
+    }
+    assert_eq!(read_f32s(&format!("{}.grm.N.bin", out)), GRM_N);
+    assert_eq!(
+        read(&format!("{}.grm.id", out)),
+        "A\tA\nB\tB\nC\tC\nD\tD\nE\tE\n"
+    );
+}
+
+#[test]
+fn rel_grm() {
+    let dir = TempDir::new("grm-rel");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&["grm", &pfile, "--format", "rel", "-o", &out]);
+    let rel = read_tsv(&format!("{}.rel", out));
+    assert_eq!(
+        rel.iter().map(Vec::len).collect::<Vec<usize>>(),
+        [1, 2, 3, 4, 5]
+    );
+    for (actual, expected) in std::iter::zip(rel.concat(), GRM) {
+        assert_close(actual.parse().unwrap(), expected, 1e-12);
+    }
+    assert_eq!(read(&format!("{}.rel.id", out)), "#IID\nA\nB\nC\nD\nE\n");
+
+    // the ALT allele frequencies are those among the kept samples, here 1/6
+    // for rs1 and 1/2 for rs2
+    pgen_rs(&[
+        "grm",
+        &pfile,
+        "--format",
+        "rel",
+        "--include-sam",
+        "IID == \"A\" || IID == \"D\" || IID == \"E\"",
+        "-o",
+        &out,
+    ]);
+    let rel = read_tsv(&format!("{}.rel", out)).concat();
+    let expected = [
+        (6.0 / 5.0 + 2.0) / 2.0,
+        (2.0 / 5.0 + 0.0) / 2.0,
+        (6.0 / 5.0 + 0.0) / 2.0,
+        (-4.0 / 5.0 - 2.0) / 2.0,
+        (-4.0 / 5.0 + 0.0) / 2.0,
+        (0.0 + 2.0) / 2.0,
+    ];
+    assert_eq!(rel.len(), expected.len());
+    for (actual, expected) in std::iter::zip(rel, expected) {
+        assert_close(actual.parse().unwrap(), expected, 1e-12);
+    }
+}
+
+#[test]
+fn uncalled_pairs() {
+    let dir = TempDir::new("grm-uncalled");
+    let pfile = dir.path("f");
+    // C is never called
+    write_pfile(&pfile, "#IID\nA\nB\nC\n", PVAR, &["01.", "21.", "10."]);
+    let out = dir.path("out");
+    pgen_rs(&["grm", &pfile, "-o", &out]);
+    let grm = read_f32s(&format!("{}.grm.bin", out));
+    assert!(grm[..3].iter().all(|value| value.is_finite()));
+    assert!(grm[3..].iter().all(|value| value.is_nan()));
+    assert_eq!(
+        read_f32s(&format!("{}.grm.N.bin", out)),
+        [3.0, 3.0, 3.0, 0.0, 0.0, 0.0]
+    );
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn king_robust_kinship() {
+    let dir = TempDir::new("kinship");
+    let pfile = dir.path("f");
+    // S2 duplicates S1, and S4 misses a call
+    write_pfile(
+        &pfile,
+        "#IID\nS1\nS2\nS3\nS4\n",
+        "#CHROM\tPOS\tID\tREF\tALT\n\
+         1\t100\tv1\tA\tC\n\
+         1\t200\tv2\tA\tC\n\
+         1\t300\tv3\tA\tC\n\
+         1\t400\tv4\tA\tC\n\
+         1\t500\tv5\tA\tC\n\
+         1\t600\tv6\tA\tC\n",
+        &["1110", "1101", "0021", "220.", "1111", "0012"],
+    );
+    let out = dir.path("out");
+    pgen_rs(&["kinship", &pfile, "-o", &out]);
+    let kin0 = read_tsv(&format!("{}.kin0", out));
+    assert_eq!(
+        kin0[0].join("\t"),
+        "#IID1\tIID2\tNSNP\tHETHET\tIBS0\tKINSHIP"
+    );
+    // (HETHET - 2 IBS0) / (2 min HET) + 1/2 - (HET1 + HET2) / (4 min HET),
+    // with counts over the variants called for both samples, e.g. for S1
+    // and S3 HETHET = 2 (v1, v5), IBS0 = 2 (v3, v4) and HET1 = HET2 = 3
+    let expected = [
+        ("S1", "S2", "6", 0.5, 0.0, 0.5),
+        ("S1", "S3", "6", 1.0 / 3.0, 1.0 / 3.0, -1.0 / 3.0),
+        ("S2", "S3", "6", 1.0 / 3.0, 1.0 / 3.0, -1.0 / 3.0),
+        ("S1", "S4", "5", 0.4, 0.2, 0.0),
+        ("S2", "S4", "5", 0.4, 0.2, 0.0),
+        ("S3", "S4", "5", 0.2, 0.0, 1.0 / 6.0),
+    ];
+    assert_eq!(kin0.len(), expected.len() + 1);
+    for (row, (iid1, iid2, nsnp, hethet, ibs0, kinship)) in kin0[1..].iter().zip(expected) {
+        assert_eq!(row[..3], [iid1, iid2, nsnp]);
+        assert_close(row[3].parse().unwrap(), hethet, 1e-12);
+        assert_close(row[4].parse().unwrap(), ibs0, 1e-12);
+        assert_close(row[5].parse().unwrap(), kinship, 1e-12);
+    }
+
+    pgen_rs(&["kinship", &pfile, "--min-kinship", "0.1", "-o", &out]);
+    let pairs = read_tsv(&format!("{}.kin0", out))[1..]
+        .iter()
+        .map(|row| format!("{}-{}", row[0], row[1]))
+        .collect::<Vec<String>>();
+    assert_eq!(pairs, ["S1-S2", "S3-S4"]);
+}
+
+#[test]
+fn kinship_over_chunks() {
+    // the variants of king_robust_kinship 200 times over, spanning several
+    // words and chunks of the masks
+    let genotypes = ["1110", "1101", "0021", "220.", "1111", "0012"].repeat(200);
+    let pvar = (0..genotypes.len()).fold(
+        String::from("#CHROM\tPOS\tID\tREF\tALT\n"),
+        |pvar, var_idx| pvar + &format!("1\t{}\tv{}\tA\tC\n", var_idx + 1, var_idx),
+    );
+    let dir = TempDir::new("kinship-chunks");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, "#IID\nS1\nS2\nS3\nS4\n", &pvar, &genotypes);
+    let out = dir.path("out");
+    pgen_rs(&["kinship", &pfile, "-o", &out]);
+    let kin0 = read_tsv(&format!("{}.kin0", out));
+    let nsnps = kin0[1..]
+        .iter()
+        .map(|row| row[2].as_str())
+        .collect::<Vec<&str>>();
+    assert_eq!(nsnps, ["1200", "1200", "1200", "1000", "1000", "1000"]);
+    let kinships = [0.5, -1.0 / 3.0, -1.0 / 3.0, 0.0, 0.0, 1.0 / 6.0];
+    for (row, kinship) in kin0[1..].iter().zip(kinships) {
+        assert_close(row[5].parse().unwrap(), kinship, 1e-12);
+    }
+}
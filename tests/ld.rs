@@ -0,0 +1,157 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn pairwise_ld() {
+    let dir = TempDir::new("ld");
+    let pfile = dir.path("f");
+    write_pfile(
+        &pfile,
+        "#IID\nS1\nS2\nS3\nS4\nS5\nS6\nS7\nS8\n",
+        "#CHROM\tPOS\tID\tREF\tALT\n\
+         1\t100\tv0\tA\tC\n\
+         1\t200\tv1\tA\tC\n\
+         1\t300\tv2\tA\tC\n\
+         1\t400\tv3\tA\tC\n\
+         1\t500\tv4\tA\tC\n\
+         1\t600\tv5\tA\tC\n",
+        &[
+            "00112200", "00112200", "22110022", "01012121", "0011220.", "11111111",
+        ],
+    );
+    let out = dir.path("out");
+    pgen_rs(&["ld", &pfile, "--r2-min", "0", "-o", &out]);
+    let vcor = read_tsv(&format!("{}.vcor", out));
+    assert_eq!(
+        vcor[0].join("\t"),
+        "#CHROM_A\tPOS_A\tID_A\tCHROM_B\tPOS_B\tID_B\tUNPHASED_R2\tD'"
+    );
+    // v0 and v1 are identical and v2 their mirror image, v3 is in weak LD
+    // with them (r² = 0.125² / (0.6875 · 0.5) over all 8 samples, and 7/136
+    // with v4 over the 7 samples called for both), and v5 is monomorphic
+    let expected = [
+        ("v0", "v1", 1.0, 1.0),
+        ("v0", "v2", 1.0, -1.0),
+        ("v1", "v2", 1.0, -1.0),
+        ("v0", "v3", 1.0 / 22.0, 1.0 / 3.0),
+        ("v1", "v3", 1.0 / 22.0, 1.0 / 3.0),
+        ("v2", "v3", 1.0 / 22.0, -1.0 / 3.0),
+        ("v0", "v4", 1.0, 1.0),
+        ("v1", "v4", 1.0, 1.0),
+        ("v2", "v4", 1.0, -1.0),
+        ("v3", "v4", 7.0 / 136.0, 1.0 / 3.0),
+    ];
+    assert_eq!(vcor.len(), expected.len() + 1);
+    for (row, (id_a, id_b, r2, d_prime)) in vcor[1..].iter().zip(expected) {
+        assert_eq!((row[2].as_str(), row[5].as_str()), (id_a, id_b));
+        assert_close(row[6].parse().unwrap(), r2, 1e-12);
+        assert_close(row[7].parse().unwrap(), d_prime, 1e-12);
+    }
+
+    // windows of base pairs and of variants, and a single index variant
+    pgen_rs(&[
+        "ld",
+        &pfile,
+        "--r2-min",
+        "0",
+        "--window-bp",
+        "150",
+        "-o",
+        &out,
+    ]);
+    let pairs = |out: &str| {
+        read_tsv(&format!("{}.vcor", out))[1..]
+            .iter()
+            .map(|row| format!("{}-{}", row[2], row[5]))
+            .collect::<Vec<String>>()
+    };
+    assert_eq!(pairs(&out), ["v0-v1", "v1-v2", "v2-v3", "v3-v4"]);
+    pgen_rs(&[
+        "ld",
+        &pfile,
+        "--r2-min",
+        "0",
+        "--window-variants",
+        "2",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(
+        pairs(&out),
+        ["v0-v1", "v0-v2", "v1-v2", "v1-v3", "v2-v3", "v2-v4", "v3-v4"]
+    );
+    pgen_rs(&["ld", &pfile, "--index-var", "v3", "-o", &out]);
+    assert_eq!(pairs(&out), Vec::<String>::new());
+    pgen_rs(&[
+        "ld",
+        &pfile,
+        "--index-var",
+        "v1",
+        "--r2-min",
+        "0.5",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(pairs(&out), ["v0-v1", "v1-v2", "v1-v4"]);
+}
+
+#[test]
+fn prune_windows() {
+    let dir = TempDir::new("prune");
+    let pfile = dir.path("f");
+    // v3 and v4 are close copies (r² ≈ 0.89, with a lower MAF) of v0 and v1,
+    // three variants away, and so is w0 of w1 on another chromosome; all
+    // other pairs have an r² below 0.03
+    write_pfile(
+        &pfile,
+        "#IID\nS1\nS2\nS3\nS4\nS5\nS6\nS7\nS8\n",
+        "#CHROM\tPOS\tID\tREF\tALT\n\
+         1\t100\tv0\tA\tC\n\
+         1\t200\tv1\tA\tC\n\
+         1\t300\tv2\tA\tC\n\
+         1\t400\tv3\tA\tC\n\
+         1\t500\tv4\tA\tC\n\
+         2\t100\tw0\tA\tC\n\
+         2\t200\tw1\tA\tC\n",
+        &[
+            "00002222", "00220022", "02020202", "00002221", "00220021", "02020201", "02020202",
+        ],
+    );
+    let out = dir.path("out");
+    let prune = |window: &str, step: &str| {
+        pgen_rs(&[
+            "prune", &pfile, "--window", window, "--step", step, "-o", &out,
+        ]);
+        (
+            read(&format!("{}.prune.in", out)),
+            read(&format!("{}.prune.out", out)),
+        )
+    };
+    // windows of 3 never hold both variants of the pairs on chromosome 1
+    assert_eq!(
+        prune("3", "1"),
+        ("v0\nv1\nv2\nv3\nv4\nw1\n".into(), "w0\n".into())
+    );
+    // windows of 4 do, as long as they move along 1 variant at a time
+    assert_eq!(
+        prune("4", "1"),
+        ("v0\nv1\nv2\nw1\n".into(), "v3\nv4\nw0\n".into())
+    );
+    assert_eq!(
+        prune("4", "3"),
+        ("v0\nv1\nv2\nv4\nw1\n".into(), "v3\nw0\n".into())
+    );
+
+    let vcf = dir.path("out.vcf");
+    pgen_rs(&["prune", &pfile, "--r2", "0.9", "--vcf", "-o", &out]);
+    assert_eq!(read(&format!("{}.prune.out", out)), "");
+    pgen_rs(&["prune", &pfile, "--r2", "0.5", "--vcf", "-o", &out]);
+    let ids = body_lines(&vcf)
+        .iter()
+        .map(|line| line.split('\t').nth(2).unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(ids, ["v0", "v1", "v2", "w1"]);
+
+    pgen_rs_fails(&["prune", &pfile, "--step", "0", "-o", &out]);
+}
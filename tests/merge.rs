@@ -0,0 +1,90 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn merge_pfiles() {
+    let dir = TempDir::new("merge");
+    let (pfile1, pfile2) = (dir.path("cohort1"), dir.path("cohort2"));
+    write_pfile(
+        &pfile1,
+        "#IID\tSEX\nA\t1\nB\t2\n",
+        "##fileformat=VCFv4.3\n\
+         #CHROM\tPOS\tID\tREF\tALT\n\
+         2\t50\trs3\tG\tA\n\
+         1\t100\trs1\tA\tG\n",
+        &["01", "2."],
+    );
+    // x1 is rs1 with REF and ALT swapped, and B is in both pfiles
+    write_pfile(
+        &pfile2,
+        "#IID\tAGE\nB\t40\nC\t50\n",
+        "##fileformat=VCFv4.2\n\
+         ##contig=<ID=chr1,length=1000>\n\
+         #CHROM\tPOS\tID\tREF\tALT\tQUAL\n\
+         chr1\t200\trs2\tC\tT\t40\n\
+         chr1\t100\tx1\tG\tA\t30\n",
+        &["12", "02"],
+    );
+    let out = dir.path("out");
+    pgen_rs(&["merge", &pfile1, &pfile2, "-o", &out]);
+    assert_eq!(
+        read(&format!("{}.psam", out)),
+        "#IID\tSEX\tAGE\nA\t1\tNA\nB\t2\tNA\nC\tNA\t50\n"
+    );
+    assert_eq!(
+        read(&format!("{}.pvar", out)),
+        "##fileformat=VCFv4.3\n\
+         ##contig=<ID=chr1,length=1000>\n\
+         #CHROM\tPOS\tID\tREF\tALT\tQUAL\n\
+         1\t100\trs1\tA\tG\t.\n\
+         chr1\t200\trs2\tC\tT\t40\n\
+         2\t50\trs3\tG\tA\t.\n"
+    );
+    // B misses rs1 in the first pfile, so gets its flipped call in the second
+    assert_eq!(read_genotypes(&out), ["220", ".12", "01."]);
+
+    // the expressions apply to each pfile
+    pgen_rs(&[
+        "merge",
+        &pfile1,
+        &pfile2,
+        "--include-var",
+        "ID != \"rs1\"",
+        "--include-sam",
+        "IID != \"A\"",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(
+        read(&format!("{}.psam", out)),
+        "#IID\tSEX\tAGE\nB\t2\tNA\nC\tNA\t50\n"
+    );
+    assert_eq!(
+        body_lines(&format!("{}.pvar", out)),
+        [
+            "chr1\t100\tx1\tG\tA\t30",
+            "chr1\t200\trs2\tC\tT\t40",
+            "2\t50\trs3\tG\tA\t.",
+        ]
+    );
+    assert_eq!(read_genotypes(&out), ["02", "12", "1."]);
+}
+
+#[test]
+fn merge_matches_fid() {
+    let dir = TempDir::new("merge-fid");
+    let (pfile1, pfile2, pfile3) = (dir.path("f1"), dir.path("f2"), dir.path("f3"));
+    let pvar = "#CHROM\tPOS\tID\tREF\tALT\n1\t100\trs1\tA\tG\n";
+    write_pfile(&pfile1, "#FID\tIID\nF1\tA\nF2\tA\n", pvar, &["01"]);
+    write_pfile(&pfile2, "#FID\tIID\nF2\tA\nF3\tA\n", pvar, &[".2"]);
+    // without a FID column, A matches the first sample A
+    write_pfile(&pfile3, "#IID\tSEX\nA\t1\n", pvar, &["2"]);
+    let out = dir.path("out");
+    pgen_rs(&["merge", &pfile1, &pfile2, &pfile3, "-o", &out]);
+    assert_eq!(
+        read(&format!("{}.psam", out)),
+        "#FID\tIID\tSEX\nF1\tA\tNA\nF2\tA\tNA\nF3\tA\tNA\n"
+    );
+    assert_eq!(read_genotypes(&out), ["012"]);
+}
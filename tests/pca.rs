@@ -0,0 +1,95 @@
+mod common;
+
+use common::*;
+use nalgebra::{DMatrix, DVector, SymmetricEigen};
+
+/// Genotypes of S1 to S6, with no missing ones.
+const GROUPED_GENOTYPES: [&str; 8] = [
+    "000222", "001221", "010212", "100122", "011202", "012111", "000122", "111211",
+];
+
+#[test]
+fn top_components() {
+    let dir = TempDir::new("pca");
+    let pfile = dir.path("f");
+    // S1 to S3 mostly carry the REF alleles and S4 to S6 the ALT ones
+    write_pfile(
+        &pfile,
+        "#IID\nS1\nS2\nS3\nS4\nS5\nS6\n",
+        "#CHROM\tPOS\tID\tREF\tALT\n\
+         1\t100\tv0\tA\tC\n\
+         1\t200\tv1\tA\tC\n\
+         1\t300\tv2\tA\tC\n\
+         1\t400\tv3\tA\tC\n\
+         1\t500\tv4\tA\tC\n\
+         1\t600\tv5\tA\tC\n\
+         1\t700\tv6\tA\tC\n\
+         1\t800\tv7\tA\tC\n",
+        &GROUPED_GENOTYPES,
+    );
+    let out = dir.path("out");
+    pgen_rs(&["pca", &pfile, "-n", "2", "-o", &out]);
+    let eigenvec = read_tsv(&format!("{}.eigenvec", out));
+    assert_eq!(eigenvec[0], ["#IID", "PC1", "PC2"]);
+    let pcs = (1..=2)
+        .map(|pc| {
+            DVector::from_iterator(6, eigenvec[1..].iter().map(|row| row[pc].parse().unwrap()))
+        })
+        .collect::<Vec<DVector<f64>>>();
+    let eigenvals = read(&format!("{}.eigenval", out))
+        .lines()
+        .map(|line| line.parse().unwrap())
+        .collect::<Vec<f64>>();
+    assert_eq!(eigenvals.len(), 2);
+
+    // with no missing genotypes the components are the top eigenvectors of
+    // XᵀX / M for the standardized genotypes X of the M variants, which the
+    // randomized subspace covers in full for 6 samples
+    let standardized = GROUPED_GENOTYPES
+        .iter()
+        .flat_map(|genotypes| {
+            let alt_counts = genotypes
+                .chars()
+                .map(|genotype| genotype.to_digit(10).unwrap() as f64)
+                .collect::<Vec<f64>>();
+            let alt_freq = alt_counts.iter().sum::<f64>() / 12.0;
+            let sd = (2.0 * alt_freq * (1.0 - alt_freq)).sqrt();
+            alt_counts
+                .into_iter()
+                .map(move |alt_count| (alt_count - 2.0 * alt_freq) / sd)
+        })
+        .collect::<Vec<f64>>();
+    let x = DMatrix::from_row_slice(GROUPED_GENOTYPES.len(), 6, &standardized);
+    let grm = x.transpose() * x / GROUPED_GENOTYPES.len() as f64;
+    let eigen = SymmetricEigen::new(grm);
+    let mut order = (0..6).collect::<Vec<usize>>();
+    order.sort_by(|a, b| eigen.eigenvalues[*b].total_cmp(&eigen.eigenvalues[*a]));
+    for (pc, eigen_idx) in order[..2].iter().enumerate() {
+        assert_close(eigenvals[pc], eigen.eigenvalues[*eigen_idx], 1e-6);
+        assert_close(pcs[pc].norm(), 1.0, 1e-6);
+        assert_close(
+            pcs[pc].dot(&eigen.eigenvectors.column(*eigen_idx)).abs(),
+            1.0,
+            1e-6,
+        );
+    }
+    // the first component tells the two groups apart
+    let pc1 = &pcs[0];
+    assert!((0..3).all(|sam_idx| pc1[sam_idx] * pc1[0] > 0.0));
+    assert!((3..6).all(|sam_idx| pc1[sam_idx] * pc1[0] < 0.0));
+}
+
+#[test]
+fn nothing_kept() {
+    let dir = TempDir::new("pca-nothing-kept");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let stderr = pgen_rs_fails(&["pca", &pfile, "--include-sam", "IID == \"Z\""]);
+    assert!(stderr.contains("no samples are kept"), "{}", stderr);
+    let stderr = pgen_rs_fails(&["pca", &pfile, "--include-var", "ID == \"rs3\""]);
+    assert!(
+        stderr.contains("no polymorphic variants are kept"),
+        "{}",
+        stderr
+    );
+}
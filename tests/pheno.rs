@@ -0,0 +1,88 @@
+mod common;
+
+use common::*;
+
+const PVAR: &str = "#CHROM\tPOS\tID\tREF\tALT\n\
+                    1\t100\trs1\tA\tG\n\
+                    1\t200\trs2\tC\tT\n";
+const GENOTYPES: [&str; 2] = ["012", "2.1"];
+
+#[test]
+fn pheno_columns() {
+    let dir = TempDir::new("pheno");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, "#IID\tSEX\nA\t1\nB\t2\nC\t1\n", PVAR, &GENOTYPES);
+    // its SEX column takes precedence over that of the .psam, and Z is not
+    // in the pfile
+    let pheno = dir.path("pheno.tsv");
+    std::fs::write(&pheno, "IID\tBATCH\tSEX\nC\tb2\t9\nA\tb1\t9\nZ\tb1\t9\n").unwrap();
+    let stdout = pgen_rs(&[
+        "--pheno",
+        &pheno,
+        "query",
+        &pfile,
+        "--samples",
+        "-f",
+        "IID + \" \" + BATCH + \" \" + SEX",
+    ]);
+    assert_eq!(stdout, "A b1 9\nB NA NA\nC b2 9\n");
+
+    let vcf = dir.path("f.vcf");
+    pgen_rs(&[
+        "--pheno",
+        &pheno,
+        "filter",
+        &pfile,
+        "--include-sam",
+        "BATCH != \"b2\"",
+        "-o",
+        &vcf,
+    ]);
+    assert_eq!(
+        body_lines(&vcf),
+        [
+            "1\t100\trs1\tA\tG\t.\t.\t.\tGT\t0/0\t0/1",
+            "1\t200\trs2\tC\tT\t.\t.\t.\tGT\t1/1\t./.",
+        ]
+    );
+}
+
+#[test]
+fn pheno_matched_by_fid() {
+    let dir = TempDir::new("pheno-fid");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, "#FID\tIID\nF1\tA\nF2\tA\nF1\tB\n", PVAR, &GENOTYPES);
+    let pheno = dir.path("pheno.tsv");
+    std::fs::write(&pheno, "FID\tIID\tQC\nF2\tA\tpass\nF1\tB\tfail\n").unwrap();
+    let stdout = pgen_rs(&[
+        "--pheno",
+        &pheno,
+        "query",
+        &pfile,
+        "--samples",
+        "-i",
+        "QC != \"fail\"",
+        "-f",
+        "FID + \":\" + IID + \" \" + QC",
+    ]);
+    assert_eq!(stdout, "F1:A NA\nF2:A pass\n");
+}
+
+#[test]
+fn pheno_duplicate_samples() {
+    let dir = TempDir::new("pheno-duplicates");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, "#FID\tIID\nF1\tA\nF2\tA\nF1\tB\n", PVAR, &GENOTYPES);
+    let pheno = dir.path("pheno.tsv");
+    // the same IID in two families is fine, and the header is taken in any case
+    std::fs::write(&pheno, "fid\tiid\tQC\nF1\tA\tpass\nF2\tA\tfail\n").unwrap();
+    let stdout = pgen_rs(&["--pheno", &pheno, "query", &pfile, "--samples", "-f", "QC"]);
+    assert_eq!(stdout, "pass\nfail\nNA\n");
+
+    std::fs::write(&pheno, "FID\tIID\tQC\nF1\tA\tpass\nF1\tA\tfail\n").unwrap();
+    let stderr = pgen_rs_fails(&["--pheno", &pheno, "query", &pfile, "--samples", "-f", "QC"]);
+    assert!(stderr.contains("duplicate sample A"), "{}", stderr);
+    std::fs::write(&pheno, "IID\tQC\nA\tpass\nA\tfail\n").unwrap();
+    let stderr = pgen_rs_fails(&["--pheno", &pheno, "query", &pfile, "--samples", "-f", "QC"]);
+    assert!(stderr.contains("duplicate sample A"), "{}", stderr);
+}
@@ -0,0 +1,59 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn polygenic_scores() {
+    let dir = TempDir::new("score");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    // the effect allele of rs2 is its REF, rs3 has neither of its alleles
+    // and rsX is not in the pfile
+    let score_file = dir.path("scores.tsv");
+    std::fs::write(
+        &score_file,
+        "ID\tA1\tWEIGHT\tW2\n\
+         rs1\tG\t0.5\t1\n\
+         rs2\tC\t2\t-1\n\
+         rs3\tT\t10\t10\n\
+         rsX\tA\t1\t1\n",
+    )
+    .unwrap();
+    let out = dir.path("out");
+    pgen_rs(&[
+        "score",
+        &pfile,
+        &score_file,
+        "--weight-col",
+        "WEIGHT,W2",
+        "-o",
+        &out,
+    ]);
+    // B misses rs2, whose mean effect allele (REF) count is 1
+    assert_eq!(
+        read(&format!("{}.sscore", out)),
+        "#IID\tALLELE_CT\tNAMED_ALLELE_DOSAGE_SUM\tWEIGHT_SUM\tW2_SUM\n\
+         A\t4\t0\t0\t0\n\
+         B\t2\t1\t2.5\t0\n\
+         C\t4\t3\t3\t1\n\
+         D\t4\t1\t2\t-1\n\
+         E\t4\t3\t4.5\t-1\n"
+    );
+
+    // the mean is among the kept samples, here 2 REF alleles
+    pgen_rs(&[
+        "score",
+        &pfile,
+        &score_file,
+        "--include-sam",
+        "IID == \"B\" || IID == \"E\"",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(
+        read(&format!("{}.sscore", out)),
+        "#IID\tALLELE_CT\tNAMED_ALLELE_DOSAGE_SUM\tWEIGHT_SUM\n\
+         B\t2\t1\t4.5\n\
+         E\t4\t3\t4.5\n"
+    );
+}
@@ -0,0 +1,168 @@
+mod common;
+
+use common::*;
+
+const PSAM: &str = "#IID\tSEX\nA\t1\nB\t2\nC\t1\n";
+const PVAR: &str = "##fileformat=VCFv4.3\n\
+                    ##contig=<ID=1,length=1000>\n\
+                    ##contig=<ID=2,length=500>\n\
+                    #CHROM\tPOS\tID\tREF\tALT\n\
+                    1\t100\trs1\tA\tG\n\
+                    1\t200\trs2\tC\tT\n\
+                    1\t300\trs3\tG\tA\n\
+                    2\t50\trs4\tT\tC\n\
+                    2\t60\trs5\tA\tC\n";
+const GENOTYPES: [&str; 5] = ["012", "2.1", "000", "111", "20."];
+
+#[test]
+fn split_by_chrom() {
+    let dir = TempDir::new("split-chrom");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&["split", &pfile, "--by", "chrom", "-o", &out]);
+    // each chromosome keeps only its own ##contig line
+    assert_eq!(
+        read(&format!("{}.1.pvar", out)),
+        "##fileformat=VCFv4.3\n\
+         ##contig=<ID=1,length=1000>\n\
+         #CHROM\tPOS\tID\tREF\tALT\n\
+         1\t100\trs1\tA\tG\n\
+         1\t200\trs2\tC\tT\n\
+         1\t300\trs3\tG\tA\n"
+    );
+    assert_eq!(read_genotypes(&format!("{}.1", out)), GENOTYPES[..3]);
+    assert_eq!(
+        read(&format!("{}.2.pvar", out)),
+        "##fileformat=VCFv4.3\n\
+         ##contig=<ID=2,length=500>\n\
+         #CHROM\tPOS\tID\tREF\tALT\n\
+         2\t50\trs4\tT\tC\n\
+         2\t60\trs5\tA\tC\n"
+    );
+    assert_eq!(read_genotypes(&format!("{}.2", out)), GENOTYPES[3..]);
+    for chrom in ["1", "2"] {
+        assert_eq!(read(&format!("{}.{}.psam", out, chrom)), PSAM);
+    }
+
+    pgen_rs(&[
+        "split",
+        &pfile,
+        "--by",
+        "chrom",
+        "--include-sam",
+        "IID != \"B\"",
+        "--vcf",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(
+        body_lines(&format!("{}.2.vcf", out)),
+        [
+            "2\t50\trs4\tT\tC\t.\t.\t.\tGT\t0/1\t0/1",
+            "2\t60\trs5\tA\tC\t.\t.\t.\tGT\t1/1\t./.",
+        ]
+    );
+
+    // the variants of chromosome 1 are not contiguous
+    std::fs::write(
+        format!("{}.pvar", pfile),
+        PVAR.replace("2\t50\trs4", "1\t400\trs4")
+            .replace("1\t300\trs3", "2\t40\trs3"),
+    )
+    .unwrap();
+    let stderr = pgen_rs_fails(&["split", &pfile, "--by", "chrom", "-o", &out]);
+    assert!(
+        stderr.contains("chromosome 1 are not contiguous"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn split_into_chunks() {
+    let dir = TempDir::new("split-chunks");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    // the first chunk takes the variant left over
+    pgen_rs(&["split", &pfile, "--chunks", "2", "-o", &out]);
+    let chunks = [format!("{}.chunk1", out), format!("{}.chunk2", out)];
+    assert_eq!(read_genotypes(&chunks[0]), GENOTYPES[..3]);
+    assert_eq!(read_genotypes(&chunks[1]), GENOTYPES[3..]);
+    // concatenating the chunks gives back the pfile
+    let joined = dir.path("joined");
+    pgen_rs(&["concat", &chunks[0], &chunks[1], "-o", &joined]);
+    assert_eq!(read(&format!("{}.pvar", joined)), PVAR);
+    assert_eq!(read(&format!("{}.psam", joined)), PSAM);
+    assert_eq!(read_genotypes(&joined), GENOTYPES);
+
+    // fewer kept variants than chunks leave the last ones empty
+    pgen_rs(&[
+        "split",
+        &pfile,
+        "--chunks",
+        "4",
+        "--include-var",
+        "CHROM == \"2\"",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(
+        body_lines(&format!("{}.chunk1.pvar", out)),
+        ["2\t50\trs4\tT\tC"]
+    );
+    assert_eq!(
+        body_lines(&format!("{}.chunk2.pvar", out)),
+        ["2\t60\trs5\tA\tC"]
+    );
+    for chunk in ["chunk3", "chunk4"] {
+        let chunk = format!("{}.{}", out, chunk);
+        assert!(body_lines(&format!("{}.pvar", chunk)).is_empty());
+        assert!(read_genotypes(&chunk).is_empty());
+        assert_eq!(read(&format!("{}.psam", chunk)), PSAM);
+    }
+
+    pgen_rs_fails(&["split", &pfile, "--chunks", "0", "-o", &out]);
+}
+
+#[test]
+fn split_vcf_threads() {
+    // enough variants per chromosome for several chunks of VCF lines
+    let pvar = (0..2000).fold(
+        "#CHROM\tPOS\tID\tREF\tALT\n".to_string(),
+        |pvar, var_idx| {
+            pvar + &format!(
+                "{}\t{}\tv{}\tA\tC\n",
+                1 + var_idx / 1000,
+                var_idx + 1,
+                var_idx
+            )
+        },
+    );
+    let genotypes = (0..2000)
+        .map(|var_idx| ["012", "2.1", "000", "111", "20."][var_idx % 5])
+        .collect::<Vec<&str>>();
+    let dir = TempDir::new("split-threads");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, &pvar, &genotypes);
+    let (out1, out3) = (dir.path("out1"), dir.path("out3"));
+    for (threads, out) in [("1", &out1), ("3", &out3)] {
+        pgen_rs(&[
+            "--threads",
+            threads,
+            "split",
+            &pfile,
+            "--by",
+            "chrom",
+            "--vcf",
+            "-o",
+            out,
+        ]);
+    }
+    for chrom in ["1", "2"] {
+        let lines = body_lines(&format!("{}.{}.vcf", out1, chrom));
+        assert_eq!(lines.len(), 1000);
+        assert_eq!(lines, body_lines(&format!("{}.{}.vcf", out3, chrom)));
+    }
+}
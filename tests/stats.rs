@@ -0,0 +1,161 @@
+mod common;
+
+use common::*;
+
+#[test]
+fn variant_stats() {
+    let dir = TempDir::new("stats-variants");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&["stats", &pfile, "-o", &out]);
+    assert_eq!(
+        read(&format!("{}.afreq", out)),
+        "#CHROM\tID\tREF\tALT\tALT_CTS\tALT_FREQS\tOBS_CT\n\
+         1\trs1\tA\tG\t4\t0.4\t10\n\
+         1\trs2\tC\tT\t4\t0.5\t8\n\
+         2\trs3\tG\tA\t0\t0\t10\n"
+    );
+    assert_eq!(
+        read(&format!("{}.vmiss", out)),
+        "#CHROM\tID\tMISSING_CT\tOBS_CT\tF_MISS\n\
+         1\trs1\t0\t5\t0\n\
+         1\trs2\t1\t5\t0.2\n\
+         2\trs3\t0\t5\t0\n"
+    );
+    assert_eq!(
+        read(&format!("{}.gcount", out)),
+        "#CHROM\tID\tREF\tALT\tHOM_REF_CT\tHET_REF_ALT_CTS\tTWO_ALT_GENO_CTS\tMISSING_CT\n\
+         1\trs1\tA\tG\t2\t2\t1\t0\n\
+         1\trs2\tC\tT\t1\t2\t1\t1\n\
+         2\trs3\tG\tA\t5\t0\t0\t0\n"
+    );
+
+    // over the kept samples only
+    pgen_rs(&[
+        "stats",
+        &pfile,
+        "--include-sam",
+        "SEX == \"1\"",
+        "--include-var",
+        "ID == \"rs1\"",
+        "-o",
+        &out,
+    ]);
+    assert_eq!(
+        body_lines(&format!("{}.afreq", out)),
+        ["1\trs1\tA\tG\t2\t0.5\t4"]
+    );
+}
+
+#[test]
+fn sample_stats() {
+    let dir = TempDir::new("stats-samples");
+    let pfile = dir.path("f");
+    write_pfile(&pfile, PSAM, PVAR, &GENOTYPES);
+    let out = dir.path("out");
+    pgen_rs(&["stats", &pfile, "--samples", "-o", &out]);
+    assert_eq!(
+        read(&format!("{}.smiss", out)),
+        "#IID\tMISSING_CT\tOBS_CT\tF_MISS\n\
+         A\t0\t3\t0\n\
+         B\t1\t3\t0.3333333333333333\n\
+         C\t0\t3\t0\n\
+         D\t0\t3\t0\n\
+         E\t0\t3\t0\n"
+    );
+
+    let het = read_tsv(&format!("{}.het", out));
+    let counts = het
+        .iter()
+        .map(|row| [&row[..4], &row[5..6]].concat().join("\t"))
+        .collect::<Vec<String>>();
+    assert_eq!(
+        counts,
+        [
+            "#IID\tHET_CT\tHOM_ALT_CT\tO(HOM)\tOBS_CT",
+            "A\t0\t1\t3\t3",
+            "B\t1\t0\t1\t2",
+            "C\t1\t1\t2\t3",
+            "D\t1\t0\t2\t3",
+            "E\t1\t0\t2\t3",
+        ]
+    );
+    // the expected homozygous calls of each variant are 1 - 2pq·2N/(2N - 1),
+    // which are 1 - 0.48·10/9, 1 - 0.5·8/7 and 1 for the three variants
+    let e_hom = 1.0 - 0.48 * 10.0 / 9.0 + 1.0 - 0.5 * 8.0 / 7.0 + 1.0;
+    let e_hom_b = 1.0 - 0.48 * 10.0 / 9.0 + 1.0;
+    let e_homs = [e_hom, e_hom_b, e_hom, e_hom, e_hom];
+    let o_homs = [3.0, 1.0, 2.0, 2.0, 2.0];
+    let obs_cts = [3.0, 2.0, 3.0, 3.0, 3.0];
+    for (sam_idx, row) in het[1..].iter().enumerate() {
+        let (e_hom, o_hom, obs_ct) = (e_homs[sam_idx], o_homs[sam_idx], obs_cts[sam_idx]);
+        assert_close(row[4].parse().unwrap(), e_hom, 1e-12);
+        assert_close(
+            row[6].parse().unwrap(),
+            (o_hom - e_hom) / (obs_ct - e_hom),
+            1e-12,
+        );
+    }
+}
+
+#[test]
+fn hwe_exact_test() {
+    let dir = TempDir::new("stats-hwe");
+    let pfile = dir.path("f");
+    write_pfile(
+        &pfile,
+        "#IID\nS1\nS2\nS3\nS4\nS5\nS6\nS7\nS8\nS9\nS10\n",
+        "#CHROM\tPOS\tID\tREF\tALT\n\
+         1\t1\th1\tA\tC\n\
+         1\t2\th2\tA\tC\n\
+         1\t3\th3\tA\tC\n\
+         1\t4\th4\tA\tC\n\
+         1\t5\th5\tA\tC\n",
+        &[
+            "0000022222",
+            "0111111112",
+            "0000000012",
+            "0001111222",
+            "000000222.",
+        ],
+    );
+    let out = dir.path("out");
+    pgen_rs(&["stats", &pfile, "--hwe", "-o", &out]);
+    let hardy = read_tsv(&format!("{}.hardy", out));
+    assert_eq!(
+        hardy[0].join("\t"),
+        "#CHROM\tID\tA1\tAX\tHOM_A1_CT\tHET_A1_CT\tTWO_AX_CT\tO(HET_A1)\tE(HET_A1)\tP"
+    );
+    // the p-values sum the probabilities of the het counts (given the allele
+    // counts) no more likely than the observed one
+    let expected = [
+        ("h1", ["5", "0", "5"], 0.0, 0.5, 0.0013639611162830976),
+        ("h2", ["1", "8", "1"], 0.8, 0.5, 0.19980947844724933),
+        ("h3", ["8", "1", "1"], 0.1, 0.255, 0.15789473684210525),
+        ("h4", ["3", "4", "3"], 0.4, 0.5, 0.5635324427894087),
+        ("h5", ["6", "0", "3"], 0.0, 4.0 / 9.0, 0.004524886877828055),
+    ];
+    for (row, (id, counts, o_het, e_het, p)) in hardy[1..].iter().zip(expected) {
+        assert_eq!(row[1], id);
+        assert_eq!(row[4..7], counts);
+        assert_close(row[7].parse().unwrap(), o_het, 1e-12);
+        assert_close(row[8].parse().unwrap(), e_het, 1e-12);
+        assert_close(row[9].parse().unwrap(), p, 1e-9);
+    }
+
+    let vcf = dir.path("f.vcf");
+    pgen_rs(&[
+        "filter",
+        &pfile,
+        "--include-var",
+        "HWE_P > 0.05",
+        "-o",
+        &vcf,
+    ]);
+    let ids = body_lines(&vcf)
+        .iter()
+        .map(|line| line.split('\t').nth(2).unwrap().to_string())
+        .collect::<Vec<String>>();
+    assert_eq!(ids, ["h2", "h3", "h4"]);
+}